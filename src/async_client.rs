@@ -0,0 +1,722 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::auth::Auth;
+#[cfg(feature = "service-account")]
+use crate::auth::ServiceAccount;
+use crate::batch::{assemble, chunk_results, EmbedResult, WorkQueue};
+use crate::builder::{Async, ClientConfig};
+use crate::embedding_cache::EmbeddingCache;
+use crate::palm::{
+    new_reply_body, new_token_body, parse_response, BatchEmbedBody, ChatBody, ChatRes, EmbedBody,
+    EmbedRes, Embedding, ListRes, Model, PalmClientBuilder, PalmError, TextBody, TextRes,
+    TokenBody, TokenRes,
+};
+use crate::rate_limit::{estimate_tokens, Cost, Method};
+use crate::retry::{retry_after, Failure};
+#[cfg(feature = "service-account")]
+use crate::service_account::parse_token_response;
+
+/// A client configured with a PaLM API key and an API endpoint, whose requests are `async`.
+///
+/// Shares the request and response types of `PalmClient`.
+/// Clones share the same connection pool.
+#[derive(Clone)]
+pub struct AsyncPalmClient {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) client: reqwest::Client,
+}
+
+/// Creates an AsyncPalmClient.
+///
+/// # Arguments
+///
+/// * `api_key` - A string that holds the PaLM API key from Google
+///
+/// # Example
+/// ```
+/// const API_KEY: &str = "api key here";
+/// let client = palm_api::palm::create_async_client(API_KEY.to_string());
+/// ```
+///
+/// # Panics
+/// Panics if the HTTP client cannot be initialized; use `AsyncPalmClient::builder()` to handle that error.
+pub fn create_async_client(api_key: String) -> AsyncPalmClient {
+    AsyncPalmClient::builder()
+        .api_key(api_key)
+        .build()
+        .expect("failed to initialize the HTTP client")
+}
+
+impl AsyncPalmClient {
+    /// Returns a `PalmClientBuilder` to configure the endpoint, timeouts, proxy and headers of a client.
+    /// Its `build()` returns an `AsyncPalmClient`.
+    pub fn builder() -> PalmClientBuilder<Async> {
+        PalmClientBuilder::with_defaults()
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: String) -> Result<T, PalmError> {
+        self.send(self.client.get(url), None).await
+    }
+
+    // Posts `json`, taking `cost` from the rate limiter before every attempt.
+    async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        url: String,
+        json: &B,
+        cost: Cost,
+    ) -> Result<T, PalmError> {
+        self.send(self.client.post(url).json(json), Some(cost))
+            .await
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        cost: Option<Cost>,
+    ) -> Result<T, PalmError> {
+        let mut attempt = 1;
+        #[cfg(feature = "service-account")]
+        let mut reauthorized = false;
+        loop {
+            if let Some(cost) = cost {
+                self.wait_for_quota(cost).await;
+            }
+            let current = request.try_clone().ok_or_else(|| {
+                PalmError::InvalidArgument("request body cannot be retried".to_string())
+            })?;
+            let mut access_token = None;
+            let failure = match self.send_once(current, &mut access_token).await {
+                Ok(value) => return Ok(value),
+                Err(failure) => failure,
+            };
+            let error = self.config.redact(failure.error, access_token.as_deref());
+            // The token may have been revoked before it expired, so a new one is fetched once.
+            #[cfg(feature = "service-account")]
+            if let (Auth::ServiceAccount(account), PalmError::Unauthorized(_), Some(token)) =
+                (&self.config.auth, &error, &access_token)
+            {
+                if !reauthorized {
+                    reauthorized = true;
+                    account.discard_token(token);
+                    continue;
+                }
+            }
+            let policy = &self.config.retry_policy;
+            match policy.next_delay(attempt, &error, failure.status, failure.retry_after) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+
+    // Sends `request` once, keeping the access token it carries in `access_token`.
+    async fn send_once<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        access_token: &mut Option<String>,
+    ) -> Result<T, Failure> {
+        *access_token = self.access_token().await?;
+        let headers = self.config.auth_headers(access_token.as_deref())?;
+        let res = request.headers(headers).send().await?;
+        let status = res.status();
+        let retry_after = retry_after(res.headers());
+        let body = res.text().await?;
+        parse_response(status, &body).map_err(|error| Failure {
+            error,
+            status: Some(status),
+            retry_after,
+        })
+    }
+
+    // Returns what a request to `method` with the prompt `texts` takes from the rate limiter.
+    async fn cost(&self, method: Method, model: &str, texts: Vec<&str>) -> Result<Cost, PalmError> {
+        let tokens = match &self.config.rate_limiter {
+            Some(limiter) if limiter.counts_tokens_with_api(method) => {
+                let messages = texts.into_iter().map(str::to_string).collect();
+                self.count_message_tokens(model.to_string(), messages)
+                    .await?
+            }
+            Some(limiter) if limiter.limits_tokens(method) => estimate_tokens(texts),
+            _ => 0,
+        };
+        Ok(Cost { method, tokens })
+    }
+
+    // Waits until the rate limiter lets a request of `cost` through.
+    async fn wait_for_quota(&self, cost: Cost) {
+        if let Some(limiter) = &self.config.rate_limiter {
+            while let Err(wait) = limiter.try_acquire(cost.method, cost.tokens) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    // Returns the access token of a service account or token provider.
+    async fn access_token(&self) -> Result<Option<String>, Failure> {
+        match &self.config.auth {
+            #[cfg(feature = "service-account")]
+            Auth::ServiceAccount(account) => match account.cached_token() {
+                Some(token) => Ok(Some(token)),
+                None => self.refresh_token(account).await.map(Some),
+            },
+            // The provider may block while it refreshes its token.
+            Auth::Custom(provider) => {
+                let provider = provider.clone();
+                let token = tokio::task::spawn_blocking(move || provider.access_token())
+                    .await
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))?;
+                Ok(Some(token))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Fetches an access token, unless another task fetched one while this one waited.
+    #[cfg(feature = "service-account")]
+    async fn refresh_token(&self, account: &ServiceAccount) -> Result<String, Failure> {
+        let _refresh = account.refresh_lock_async().await;
+        if let Some(token) = account.cached_token() {
+            return Ok(token);
+        }
+        let (token_uri, form) = account.token_request()?;
+        let res = self.client.post(token_uri).form(&form).send().await?;
+        let status = res.status();
+        let retry_after = retry_after(res.headers());
+        let body = res.text().await?;
+        let token = parse_token_response(status, &body).map_err(|error| Failure {
+            error,
+            status: Some(status),
+            retry_after,
+        })?;
+        Ok(account.store_token(token))
+    }
+
+    /// Lists models available through the API.
+    ///
+    /// # Example
+    /// ```
+    /// let model_list = client.list_models().await.expect("err");
+    /// for model in model_list {
+    ///     println!("{}",model.name);
+    /// }
+    /// ```
+    pub async fn list_models(&self) -> Result<Vec<Model>, PalmError> {
+        let parsed_models: ListRes = self.fetch(self.config.url("models")).await?;
+        Ok(parsed_models.models)
+    }
+
+    /// Gets information about a specific Model.
+    /// Models are cached by the client, see `PalmClientBuilder::model_cache_ttl`.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    ///
+    /// # Example
+    /// ```
+    /// let model = client.get_model("text-bison-001".to_string()).await.expect("err");
+    /// println!("{}",model.description);
+    /// ```
+    pub async fn get_model(&self, model: String) -> Result<Model, PalmError> {
+        if let Some(model_info) = self.config.model_cache.get(&model) {
+            return Ok(model_info);
+        }
+        let model_info = self
+            .fetch(self.config.url(&format!("models/{}", model)))
+            .await?;
+        self.config.model_cache.insert(&model, &model_info);
+        Ok(model_info)
+    }
+
+    /// Removes `model` from the model cache, so that the next `get_model()` fetches it again.
+    pub fn invalidate_model(&self, model: &str) {
+        self.config.model_cache.invalidate(Some(model));
+    }
+
+    /// Empties the model cache.
+    pub fn clear_model_cache(&self) {
+        self.config.model_cache.invalidate(None);
+    }
+
+    /// Returns the embedding cache set with `PalmClientBuilder::embedding_cache`, e.g. to read its statistics.
+    pub fn embedding_cache(&self) -> Option<&EmbeddingCache> {
+        self.config.embedding_cache.as_deref()
+    }
+
+    /// Runs a model's tokenizer on a string and returns the token count.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `message_list` - A vector of text that should be provided to the model first
+    pub async fn count_message_tokens(
+        &self,
+        model: String,
+        message_list: Vec<String>,
+    ) -> Result<u32, PalmError> {
+        self.count_tokens(model, &new_token_body(message_list))
+            .await
+    }
+
+    /// Runs a model's tokenizer on the whole prompt of `chat_body`, including its context and
+    /// examples, and returns the token count.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `chat_body` - The `ChatBody` that would be sent to `chat()`
+    pub async fn count_chat_tokens(
+        &self,
+        model: String,
+        chat_body: &ChatBody,
+    ) -> Result<u32, PalmError> {
+        self.count_tokens(model, &chat_body.token_body()).await
+    }
+
+    async fn count_tokens(&self, model: String, token_body: &TokenBody) -> Result<u32, PalmError> {
+        let cost = Cost {
+            method: Method::CountMessageTokens,
+            tokens: 0,
+        };
+        let parsed_token: TokenRes = self
+            .post(
+                self.config
+                    .url(&format!("models/{}:countMessageTokens", model)),
+                token_body,
+                cost,
+            )
+            .await?;
+        Ok(parsed_token.token_count)
+    }
+
+    /// Generates an embedding from the model given an input message.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `text` - The free-form input text that the model will turn into an embedding
+    pub async fn generate_embeddings(
+        &self,
+        model: String,
+        text: String,
+    ) -> Result<Embedding, PalmError> {
+        let Some(cache) = &self.config.embedding_cache else {
+            return self.embed_text(model, text).await;
+        };
+        let (key_model, key_text) = (model.clone(), text.clone());
+        if let Some(embedding) =
+            with_cache(cache, move |cache| cache.get(&key_model, &key_text)).await
+        {
+            return Ok(embedding);
+        }
+        let embedding = self.embed_text(model, text.clone()).await?;
+        let cached = embedding.clone();
+        with_cache(cache, move |cache| cache.insert(&text, &cached)).await;
+        Ok(embedding)
+    }
+
+    async fn embed_text(&self, model: String, text: String) -> Result<Embedding, PalmError> {
+        let cost = self
+            .cost(Method::EmbedText, &model, vec![text.as_str()])
+            .await?;
+        let embed_body = EmbedBody { text };
+        let parsed_embeddings: EmbedRes = self
+            .post(
+                self.config.url(&format!("models/{}:embedText", model)),
+                &embed_body,
+                cost,
+            )
+            .await?;
+        Ok(Embedding::new(model, parsed_embeddings.embedding.value))
+    }
+
+    /// Generates an embedding for each text, sending up to 100 texts per request.
+    ///
+    /// Up to `PalmClientBuilder::batch_concurrency` requests, 4 by default, are sent from tasks
+    /// spawned on the current runtime.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `texts` - The free-form input texts that the model will turn into embeddings
+    ///
+    /// Returns one result per text, in the order of `texts`, see `PalmClient::batch_embed_texts`.
+    pub async fn batch_embed_texts(
+        &self,
+        model: String,
+        texts: Vec<String>,
+    ) -> Vec<Result<Embedding, PalmError>> {
+        let Some(cache) = &self.config.embedding_cache else {
+            return self.embed_batch(model, texts).await;
+        };
+        let key_model = model.clone();
+        let (cached, misses) = with_cache(cache, move |cache| cache.split(&key_model, texts)).await;
+        let embedded = self.embed_batch(model, misses.clone()).await;
+        with_cache(cache, move |cache| cache.merge(cached, &misses, embedded)).await
+    }
+
+    async fn embed_batch(&self, model: String, texts: Vec<String>) -> Vec<EmbedResult> {
+        let queue = Arc::new(WorkQueue::chunks(texts));
+        let handles: Vec<_> = (0..queue.workers(self.config.batch_concurrency))
+            .map(|_| {
+                let (client, model, queue) = (self.clone(), model.clone(), queue.clone());
+                tokio::spawn(async move {
+                    let mut parts = Vec::new();
+                    while let Some((offset, chunk)) = queue.next() {
+                        parts.push((offset, client.embed_chunk(&model, chunk).await));
+                    }
+                    parts
+                })
+            })
+            .collect();
+        let mut parts = Vec::new();
+        for handle in handles {
+            parts.extend(
+                handle
+                    .await
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())),
+            );
+        }
+        assemble(queue.len(), parts)
+    }
+
+    async fn embed_chunk(&self, model: &str, texts: Vec<String>) -> Vec<EmbedResult> {
+        let texts_ref = texts.iter().map(String::as_str).collect();
+        let res = match self.cost(Method::BatchEmbedText, model, texts_ref).await {
+            Ok(cost) => {
+                self.post(
+                    self.config.url(&format!("models/{}:batchEmbedText", model)),
+                    &BatchEmbedBody { texts: &texts },
+                    cost,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        match res {
+            Err(PalmError::BadRequest(_)) if texts.len() > 1 => {
+                let mut results = Vec::with_capacity(texts.len());
+                for text in texts {
+                    results.push(self.embed_text(model.to_string(), text).await);
+                }
+                results
+            }
+            res => chunk_results(model, texts.len(), res),
+        }
+    }
+
+    /// Generates a response from the model given an input ChatBody.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `chat_body` - A `ChatBody` struct to be provided to the model first
+    ///
+    /// # Example
+    /// ```
+    /// let mut chat_body = palm_api::palm::new_chat_body();
+    /// chat_body.append_message("How are you doing?".to_string());
+    /// let chat_res = client
+    ///     .chat("chat-bison-001".to_string(), chat_body)
+    ///     .await
+    ///     .expect("err");
+    /// println!("{}",chat_res.candidates.unwrap()[0].content);
+    /// ```
+    pub async fn chat(&self, model: String, chat_body: ChatBody) -> Result<ChatRes, PalmError> {
+        chat_body.validate()?;
+        let cost = self
+            .cost(Method::GenerateMessage, &model, chat_body.prompt_texts())
+            .await?;
+        let mut parsed_chat: ChatRes = self
+            .post(
+                self.config
+                    .url(&format!("models/{}:generateMessage", model)),
+                &chat_body,
+                cost,
+            )
+            .await?;
+        parsed_chat.chat_body = Some(chat_body);
+        parsed_chat.model = Some(model);
+        Ok(parsed_chat)
+    }
+
+    /// Generates a response from the model given a previous response and a new message.
+    ///
+    /// # Arguments
+    /// * `previous_response` - The previous `ChatRes` to whom the reply is being made
+    /// * `reply_message` - The message to be sent to the previous response given by model
+    /// * `candidate_index` - The index of the candidate corresponding to the message
+    ///
+    /// Returns `PalmError::MissingCandidate` if there is no candidate at `candidate_index`.
+    pub async fn reply(
+        &self,
+        previous_response: ChatRes,
+        reply_message: String,
+        candidate_index: usize,
+    ) -> Result<ChatRes, PalmError> {
+        let (model, chat_body) =
+            new_reply_body(&previous_response, reply_message, candidate_index)?;
+        self.chat(model, chat_body).await
+    }
+
+    /// Replies to several candidates of a previous response concurrently, to explore different
+    /// branches of a conversation.
+    ///
+    /// Up to `PalmClientBuilder::branch_concurrency` replies, 4 by default, are sent from tasks
+    /// spawned on the current runtime.
+    ///
+    /// # Arguments
+    /// * `previous_response` - The previous `ChatRes` to whom the replies are being made
+    /// * `replies` - Pairs of a candidate index and the message replying to that candidate
+    ///
+    /// Returns one result per reply, in the order of `replies`.
+    pub async fn branch(
+        &self,
+        previous_response: &ChatRes,
+        replies: Vec<(usize, String)>,
+    ) -> Vec<Result<ChatRes, PalmError>> {
+        let reply_bodies = replies
+            .into_iter()
+            .map(|(candidate_index, reply_message)| {
+                new_reply_body(previous_response, reply_message, candidate_index)
+            })
+            .collect();
+        let queue = Arc::new(WorkQueue::new(reply_bodies));
+        let handles: Vec<_> = (0..queue.workers(self.config.branch_concurrency))
+            .map(|_| {
+                let (client, queue) = (self.clone(), queue.clone());
+                tokio::spawn(async move {
+                    let mut parts = Vec::new();
+                    while let Some((index, reply_body)) = queue.next() {
+                        let result = match reply_body {
+                            Ok((model, chat_body)) => client.chat(model, chat_body).await,
+                            Err(err) => Err(err),
+                        };
+                        parts.push((index, vec![result]));
+                    }
+                    parts
+                })
+            })
+            .collect();
+        let mut parts = Vec::new();
+        for handle in handles {
+            parts.extend(
+                handle
+                    .await
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())),
+            );
+        }
+        assemble(queue.len(), parts)
+    }
+
+    /// Generates a response from the model given an input message.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `text_body` - A `TextBody` struct to be provided to the model first
+    ///
+    /// # Example
+    /// ```
+    /// let mut text_body = palm_api::palm::new_text_body();
+    /// text_body.set_text_prompt("Write a story about a magic backpack.".to_string());
+    /// let text_res = client
+    ///     .generate_text("text-bison-001".to_string(), text_body)
+    ///     .await
+    ///     .expect("err");
+    /// println!("{}",text_res.candidates.unwrap()[0].output);
+    /// ```
+    pub async fn generate_text(
+        &self,
+        model: String,
+        mut text_body: TextBody,
+    ) -> Result<TextRes, PalmError> {
+        text_body.validate()?;
+        if let Some(policy) = &self.config.safety_policy {
+            text_body.apply_safety_policy(policy);
+        }
+        let cost = self
+            .cost(Method::GenerateText, &model, vec![text_body.text_prompt()])
+            .await?;
+        self.post(
+            self.config.url(&format!("models/{}:generateText", model)),
+            &text_body,
+            cost,
+        )
+        .await
+    }
+}
+
+/// Runs `f` on the blocking thread pool, as the cache may read and write its directory while
+/// holding its locks.
+async fn with_cache<T, F>(cache: &Arc<EmbeddingCache>, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&EmbeddingCache) -> T + Send + 'static,
+{
+    let cache = cache.clone();
+    tokio::task::spawn_blocking(move || f(&cache))
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncPalmClient;
+    use crate::palm::{new_text_body, PalmError};
+    use crate::test_server::{Reply, TestServer};
+
+    fn test_client(server: &TestServer) -> AsyncPalmClient {
+        AsyncPalmClient::builder()
+            .api_key("test-key")
+            .base_url(&server.url)
+            .build()
+            .expect("err")
+    }
+
+    #[tokio::test]
+    async fn generate_text_inside_runtime() {
+        let server = TestServer::start(vec![Reply::json(
+            200,
+            r#"{"candidates": [{"output": "cold", "safetyRatings": []}]}"#,
+        )]);
+        let client = test_client(&server);
+        let mut text_body = new_text_body();
+        text_body.set_text_prompt("The opposite of hot is".to_string());
+        let text_res = client
+            .generate_text("text-bison-001".to_string(), text_body)
+            .await
+            .expect("err");
+        assert_eq!(text_res.candidates.unwrap()[0].output, "cold");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(
+            requests[0].path,
+            "/v1beta2/models/text-bison-001:generateText"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("test-key"));
+        assert!(!requests[0].body.contains("topK"));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = TestServer::start(vec![
+            Reply::json(503, r#"{"error": {"code": 503, "message": "overloaded"}}"#)
+                .header("Retry-After", "0"),
+            Reply::json(200, r#"{"embedding": {"value": [0.5, 0.25]}}"#),
+        ]);
+        let client = test_client(&server);
+        let embeddings = client
+            .generate_embeddings("embedding-gecko-001".to_string(), "hello".to_string())
+            .await
+            .expect("err");
+        assert_eq!(embeddings.to_vec(), vec![0.5, 0.25]);
+        assert_eq!(embeddings.model(), "embedding-gecko-001");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn get_model_not_found() {
+        let server = TestServer::start(vec![Reply::json(
+            404,
+            r#"{"error": {"code": 404, "message": "Model is not found", "status": "NOT_FOUND"}}"#,
+        )]);
+        let client = test_client(&server);
+        let err = client
+            .get_model("unknown-001".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PalmError::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn embedding_cache_on_disk() {
+        use crate::embedding_cache::EmbeddingCache;
+
+        let directory =
+            std::env::temp_dir().join(format!("palm_api-async-embeddings-{}", std::process::id()));
+        let server = TestServer::start(vec![Reply::json(
+            200,
+            r#"{"embedding": {"value": [0.5, 0.25]}}"#,
+        )]);
+        let client = AsyncPalmClient::builder()
+            .base_url(&server.url)
+            .embedding_cache(
+                EmbeddingCache::new(0)
+                    .with_directory(&directory)
+                    .expect("err"),
+            )
+            .build()
+            .expect("err");
+        for _ in 0..2 {
+            let embedding = client
+                .generate_embeddings("embedding-gecko-001".to_string(), "hello".to_string())
+                .await
+                .expect("err");
+            assert_eq!(embedding.to_vec(), vec![0.5, 0.25]);
+        }
+        let results = client
+            .batch_embed_texts("embedding-gecko-001".to_string(), vec!["hello".to_string()])
+            .await;
+        assert_eq!(results[0].as_ref().expect("err").to_vec(), vec![0.5, 0.25]);
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(client.embedding_cache().expect("err").stats().disk_hits, 2);
+        std::fs::remove_dir_all(&directory).expect("err");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn token_provider_runs_off_the_runtime() {
+        use crate::auth::TokenProvider;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        // Blocks until another task of the runtime ran, which cannot happen when the provider
+        // blocks the only thread of the runtime.
+        struct SlowProvider {
+            started: Arc<AtomicBool>,
+            progressed: Arc<AtomicBool>,
+        }
+
+        impl TokenProvider for SlowProvider {
+            fn access_token(&self) -> Result<String, PalmError> {
+                self.started.store(true, Ordering::SeqCst);
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while !self.progressed.load(Ordering::SeqCst) {
+                    if Instant::now() > deadline {
+                        return Err(PalmError::InvalidArgument(
+                            "the runtime is blocked".to_string(),
+                        ));
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Ok("slow-token".to_string())
+            }
+        }
+
+        let started = Arc::new(AtomicBool::new(false));
+        let progressed = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn({
+            let started = started.clone();
+            let progressed = progressed.clone();
+            async move {
+                while !started.load(Ordering::SeqCst) {
+                    tokio::task::yield_now().await;
+                }
+                progressed.store(true, Ordering::SeqCst);
+            }
+        });
+        let server = TestServer::start(vec![Reply::json(200, r#"{"models": []}"#)]);
+        let client = AsyncPalmClient::builder()
+            .base_url(&server.url)
+            .token_provider(SlowProvider {
+                started,
+                progressed,
+            })
+            .build()
+            .expect("err");
+        client.list_models().await.expect("err");
+        task.await.expect("err");
+        assert_eq!(
+            server.requests()[0].header("authorization"),
+            Some("Bearer slow-token")
+        );
+    }
+}
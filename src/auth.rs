@@ -0,0 +1,190 @@
+//! Credentials used to authenticate requests to the PaLM API.
+
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+use crate::error::PalmError;
+
+#[cfg(feature = "service-account")]
+pub use crate::service_account::ServiceAccount;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+const API_KEY_HEADER: &str = "x-goog-api-key";
+
+/// Supplies OAuth2 access tokens, e.g. from a metadata server or a secret store.
+///
+/// The provider is called before every request, so it should cache tokens and only block when
+/// they need to be refreshed. `AsyncPalmClient` calls it on the blocking thread pool of the
+/// runtime.
+pub trait TokenProvider: Send + Sync {
+    /// Returns a valid access token.
+    fn access_token(&self) -> Result<String, PalmError>;
+}
+
+/// How a client authenticates its requests.
+///
+/// # Example
+/// ```
+/// let client = palm_api::palm::PalmClient::builder()
+///     .auth(palm_api::auth::Auth::BearerToken(ACCESS_TOKEN.to_string()))
+///     .build()
+///     .expect("err");
+/// ```
+#[derive(Clone)]
+pub enum Auth {
+    /// An API key, sent in the `x-goog-api-key` header.
+    ApiKey(String),
+    /// A static OAuth2 access token, sent as `Authorization: Bearer`.
+    BearerToken(String),
+    /// Access tokens obtained with service account credentials.
+    #[cfg(feature = "service-account")]
+    ServiceAccount(Arc<ServiceAccount>),
+    /// Access tokens obtained from a caller-provided `TokenProvider`.
+    Custom(Arc<dyn TokenProvider>),
+}
+
+impl Default for Auth {
+    fn default() -> Auth {
+        Auth::ApiKey(String::new())
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::ApiKey(api_key) if api_key.is_empty() => f.write_str("ApiKey(\"\")"),
+            Auth::ApiKey(_) => f.write_str("ApiKey(<redacted>)"),
+            Auth::BearerToken(_) => f.write_str("BearerToken(<redacted>)"),
+            #[cfg(feature = "service-account")]
+            Auth::ServiceAccount(account) => {
+                f.debug_tuple("ServiceAccount").field(account).finish()
+            }
+            Auth::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl Auth {
+    /// Returns the API key, if requests are authenticated with one.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn api_key(&self) -> Option<&str> {
+        match self {
+            Auth::ApiKey(api_key) if !api_key.is_empty() => Some(api_key),
+            _ => None,
+        }
+    }
+
+    /// Returns the static secret that must never appear in errors. Access tokens are
+    /// redacted by the client, which knows the one it sent.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn secret(&self) -> &str {
+        match self {
+            Auth::ApiKey(secret) | Auth::BearerToken(secret) => secret,
+            _ => "",
+        }
+    }
+
+    /// Returns the headers authenticating a request.
+    /// `access_token` is the token resolved by the client for service accounts and token
+    /// providers.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn headers(
+        &self,
+        api_key_in_query: bool,
+        access_token: Option<&str>,
+    ) -> Result<HeaderMap, PalmError> {
+        let mut headers = HeaderMap::new();
+        let (name, value) = match (self, access_token) {
+            (_, Some(token)) => (AUTHORIZATION.as_str(), format!("Bearer {}", token)),
+            (Auth::ApiKey(_), None) => match self.api_key() {
+                Some(api_key) if !api_key_in_query => (API_KEY_HEADER, api_key.to_string()),
+                _ => return Ok(headers),
+            },
+            (Auth::BearerToken(token), None) => {
+                (AUTHORIZATION.as_str(), format!("Bearer {}", token))
+            }
+            (Auth::Custom(_), None) => return Ok(headers),
+            #[cfg(feature = "service-account")]
+            (Auth::ServiceAccount(_), None) => return Ok(headers),
+        };
+        let mut value = HeaderValue::from_str(&value).map_err(|_| {
+            PalmError::InvalidArgument("credentials contain invalid characters".to_string())
+        })?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+        Ok(headers)
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::{Auth, TokenProvider};
+    use crate::palm::{PalmClient, PalmError};
+    use crate::test_server::{Reply, TestServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingProvider(AtomicUsize);
+
+    impl TokenProvider for CountingProvider {
+        fn access_token(&self) -> Result<String, PalmError> {
+            let calls = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(format!("token-{}", calls))
+        }
+    }
+
+    #[test]
+    fn bearer_token_and_custom_provider() {
+        let server = TestServer::start(vec![
+            Reply::json(200, r#"{"models": []}"#),
+            Reply::json(200, r#"{"models": []}"#),
+            Reply::json(200, r#"{"models": []}"#),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .bearer_token("static-token")
+            .build()
+            .expect("err");
+        client.list_models().expect("err");
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .token_provider(CountingProvider(AtomicUsize::new(0)))
+            .build()
+            .expect("err");
+        client.list_models().expect("err");
+        client.list_models().expect("err");
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer static-token")
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), None);
+        assert_eq!(requests[1].header("authorization"), Some("Bearer token-1"));
+        assert_eq!(requests[2].header("authorization"), Some("Bearer token-2"));
+    }
+
+    #[test]
+    fn redacts_provided_token() {
+        let server = TestServer::start(vec![Reply::json(
+            400,
+            r#"{"error": {"code": 400, "message": "bad token token-1"}}"#,
+        )]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .token_provider(CountingProvider(AtomicUsize::new(0)))
+            .build()
+            .expect("err");
+        let err = client.list_models().expect_err("err");
+        assert!(matches!(err, PalmError::BadRequest(_)));
+        assert!(!err.to_string().contains("token-1"));
+    }
+
+    #[test]
+    fn debug_redacts_credentials() {
+        let debug = format!("{:?}", Auth::BearerToken("static-token".to_string()));
+        assert!(!debug.contains("static-token"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Splitting `batch_embed_texts()` into chunks embedded concurrently, and the bounded worker
+//! queue also used by `branch()`.
+
+use serde::de::Error as _;
+use std::sync::{Arc, Mutex};
+
+use crate::palm::{BatchEmbedRes, Embedding, PalmError};
+
+/// The maximum number of texts the API embeds in one `batchEmbedText` request.
+pub(crate) const MAX_BATCH_EMBED_TEXTS: usize = 100;
+/// The default number of chunks embedded concurrently.
+pub(crate) const BATCH_CONCURRENCY: usize = 4;
+/// The default number of replies of `branch()` sent concurrently.
+pub(crate) const BRANCH_CONCURRENCY: usize = 4;
+
+/// The result of embedding one text of a batch.
+pub(crate) type EmbedResult = Result<Embedding, PalmError>;
+
+/// The work of a batch, taken in order by the workers sending its requests, each item keyed by
+/// its index in the batch.
+pub(crate) struct WorkQueue<T> {
+    items: Mutex<std::vec::IntoIter<(usize, T)>>,
+    len: usize,
+}
+
+impl<T> WorkQueue<T> {
+    /// Queues one request per item.
+    pub(crate) fn new(items: Vec<T>) -> WorkQueue<T> {
+        let len = items.len();
+        WorkQueue {
+            items: Mutex::new(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            len,
+        }
+    }
+
+    /// Returns the number of results of the batch.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns how many workers to start, at most `concurrency` and one per item.
+    pub(crate) fn workers(&self, concurrency: usize) -> usize {
+        let items = self.items.lock().unwrap_or_else(|err| err.into_inner());
+        items.len().min(concurrency.max(1))
+    }
+
+    /// Takes the next item, with its index in the batch.
+    pub(crate) fn next(&self) -> Option<(usize, T)> {
+        let mut items = self.items.lock().unwrap_or_else(|err| err.into_inner());
+        items.next()
+    }
+}
+
+impl WorkQueue<Vec<String>> {
+    /// Splits `texts` into chunks of at most `MAX_BATCH_EMBED_TEXTS`, each keyed by the index of
+    /// its first text.
+    pub(crate) fn chunks(texts: Vec<String>) -> WorkQueue<Vec<String>> {
+        let len = texts.len();
+        let mut chunks = Vec::with_capacity(len.div_ceil(MAX_BATCH_EMBED_TEXTS));
+        let mut texts = texts.into_iter();
+        let mut offset = 0;
+        while offset < len {
+            let chunk: Vec<String> = texts.by_ref().take(MAX_BATCH_EMBED_TEXTS).collect();
+            let chunk_len = chunk.len();
+            chunks.push((offset, chunk));
+            offset += chunk_len;
+        }
+        WorkQueue {
+            items: Mutex::new(chunks.into_iter()),
+            len,
+        }
+    }
+}
+
+/// Returns the result of each text of a chunk of `len` texts, given the response of `model`.
+pub(crate) fn chunk_results(
+    model: &str,
+    len: usize,
+    res: Result<BatchEmbedRes, PalmError>,
+) -> Vec<EmbedResult> {
+    let res = res.and_then(|res| {
+        if res.embeddings.len() == len {
+            Ok(res.embeddings)
+        } else {
+            Err(PalmError::Decode(serde_json::Error::custom(format!(
+                "expected {} embeddings, the response has {}",
+                len,
+                res.embeddings.len()
+            ))))
+        }
+    });
+    match res {
+        Ok(embeddings) => embeddings
+            .into_iter()
+            .map(|embedding| Ok(Embedding::new(model, embedding.value)))
+            .collect(),
+        Err(err) if len == 1 => vec![Err(err)],
+        Err(err) => {
+            let err = Arc::new(err);
+            (0..len)
+                .map(|_| Err(PalmError::BatchFailed(err.clone())))
+                .collect()
+        }
+    }
+}
+
+/// Puts the results of the items, keyed by the index of their first result, back in input order.
+pub(crate) fn assemble<T>(len: usize, mut parts: Vec<(usize, Vec<T>)>) -> Vec<T> {
+    parts.sort_by_key(|(offset, _)| *offset);
+    let mut results = Vec::with_capacity(len);
+    for (_, part) in parts {
+        results.extend(part);
+    }
+    results
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::{WorkQueue, MAX_BATCH_EMBED_TEXTS};
+    use crate::palm::{PalmClient, PalmError};
+    use crate::test_server::{Reply, TestServer};
+
+    fn embeddings(count: usize) -> String {
+        let values = vec![r#"{"value": [0.5, 0.25]}"#; count];
+        format!(r#"{{"embeddings": [{}]}}"#, values.join(", "))
+    }
+
+    #[test]
+    fn work_queue_bounds_workers() {
+        let queue = WorkQueue::new(vec!["a", "b", "c"]);
+        assert_eq!(queue.workers(2), 2);
+        assert_eq!(queue.workers(8), 3);
+        assert_eq!(queue.next(), Some((0, "a")));
+        assert_eq!(queue.workers(8), 2);
+        let queue = WorkQueue::chunks(vec![String::new(); MAX_BATCH_EMBED_TEXTS + 1]);
+        assert_eq!(
+            (queue.len(), queue.workers(8)),
+            (MAX_BATCH_EMBED_TEXTS + 1, 2)
+        );
+    }
+
+    #[test]
+    fn chunks_texts_and_keeps_input_order() {
+        let server = TestServer::start(vec![
+            Reply::json(200, &embeddings(MAX_BATCH_EMBED_TEXTS)),
+            Reply::json(503, r#"{"error": {"code": 503, "message": "overloaded"}}"#),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .retry_policy(crate::retry::RetryPolicy::none())
+            .batch_concurrency(1)
+            .build()
+            .expect("err");
+        let texts = (0..MAX_BATCH_EMBED_TEXTS + 2)
+            .map(|index| format!("text {}", index))
+            .collect();
+        let results = client.batch_embed_texts("embedding-gecko-001".to_string(), texts);
+
+        assert_eq!(results.len(), MAX_BATCH_EMBED_TEXTS + 2);
+        assert_eq!(results[0].as_ref().expect("err").to_vec(), vec![0.5, 0.25]);
+        assert!(matches!(
+            &results[MAX_BATCH_EMBED_TEXTS + 1],
+            Err(PalmError::BatchFailed(err)) if matches!(**err, PalmError::ServerError(_))
+        ));
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].path,
+            "/v1beta2/models/embedding-gecko-001:batchEmbedText"
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).expect("err");
+        assert_eq!(body["texts"], serde_json::json!(["text 100", "text 101"]));
+    }
+
+    #[test]
+    fn rejected_chunks_are_embedded_one_by_one() {
+        let bad_request = r#"{"error": {"code": 400, "message": "empty text"}}"#;
+        let server = TestServer::start(vec![
+            Reply::json(400, bad_request),
+            Reply::json(200, r#"{"embedding": {"value": [1.0]}}"#),
+            Reply::json(400, bad_request),
+            Reply::json(200, r#"{"embedding": {"value": [2.0]}}"#),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let texts = vec!["one".to_string(), String::new(), "three".to_string()];
+        let results = client.batch_embed_texts("embedding-gecko-001".to_string(), texts);
+
+        assert_eq!(results[0].as_ref().expect("err").to_vec(), vec![1.0]);
+        assert!(matches!(results[1], Err(PalmError::BadRequest(_))));
+        assert_eq!(results[2].as_ref().expect("err").to_vec(), vec![2.0]);
+        assert_eq!(server.requests().len(), 4);
+    }
+}
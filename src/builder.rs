@@ -0,0 +1,560 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+#[cfg(feature = "service-account")]
+use crate::auth::ServiceAccount;
+use crate::auth::{Auth, TokenProvider};
+use crate::batch::{BATCH_CONCURRENCY, BRANCH_CONCURRENCY};
+use crate::embedding_cache::EmbeddingCache;
+use crate::model_cache::ModelCache;
+#[cfg(feature = "async")]
+use crate::palm::AsyncPalmClient;
+#[cfg(feature = "blocking")]
+use crate::palm::PalmClient;
+use crate::palm::{PalmError, ENDPOINT};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::safety::SafetyPolicy;
+
+const API_VERSION: &str = "v1beta2";
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const MODEL_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Settings shared by `PalmClient` and `AsyncPalmClient` once built.
+#[derive(Debug)]
+pub(crate) struct ClientConfig {
+    pub(crate) auth: Auth,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) model_cache: ModelCache,
+    pub(crate) safety_policy: Option<SafetyPolicy>,
+    pub(crate) batch_concurrency: usize,
+    pub(crate) branch_concurrency: usize,
+    pub(crate) embedding_cache: Option<Arc<EmbeddingCache>>,
+    pub(crate) api_key_in_query: bool,
+    pub(crate) base_url: String,
+    pub(crate) api_version: String,
+}
+
+impl ClientConfig {
+    /// Builds the URL of `path`, relative to the API version, e.g. `models/text-bison-001`.
+    pub(crate) fn url(&self, path: &str) -> String {
+        let url = format!("{}/{}/{}", self.base_url, self.api_version, path);
+        match self.auth.api_key() {
+            Some(api_key) if self.api_key_in_query => format!("{}?key={}", url, api_key),
+            _ => url,
+        }
+    }
+
+    /// Returns the headers authenticating a request.
+    /// `access_token` is the token resolved by the client for service accounts and token
+    /// providers.
+    pub(crate) fn auth_headers(&self, access_token: Option<&str>) -> Result<HeaderMap, PalmError> {
+        self.auth.headers(self.api_key_in_query, access_token)
+    }
+
+    /// Removes the credentials from `err`, including the access token sent with the request.
+    pub(crate) fn redact(&self, err: PalmError, access_token: Option<&str>) -> PalmError {
+        let err = err.redact(self.auth.secret());
+        match access_token {
+            Some(access_token) => err.redact(access_token),
+            None => err,
+        }
+    }
+}
+
+/// Configures and builds a `PalmClient`, or an `AsyncPalmClient` when created by
+/// `AsyncPalmClient::builder()`.
+///
+/// # Example
+/// ```
+/// let client = palm_api::palm::PalmClient::builder()
+///     .api_key(API_KEY)
+///     .base_url("http://localhost:8080")
+///     .timeout(std::time::Duration::from_secs(30))
+///     .user_agent("my-service/1.0")
+///     .build()
+///     .expect("err");
+/// ```
+#[derive(Debug, Clone)]
+pub struct PalmClientBuilder<K = Blocking> {
+    kind: PhantomData<K>,
+    auth: Auth,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    model_cache_ttl: Duration,
+    safety_policy: Option<SafetyPolicy>,
+    batch_concurrency: usize,
+    branch_concurrency: usize,
+    embedding_cache: Option<Arc<EmbeddingCache>>,
+    api_key_in_query: bool,
+    base_url: String,
+    api_version: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    proxies: Vec<reqwest::Proxy>,
+    no_proxy: bool,
+    headers: Vec<(String, String)>,
+    user_agent: Option<String>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    root_certificates: Vec<reqwest::Certificate>,
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    min_tls_version: Option<reqwest::tls::Version>,
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    accept_invalid_certs: bool,
+}
+
+/// Selects a `PalmClientBuilder` whose `build()` returns a blocking `PalmClient`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blocking;
+
+/// Selects a `PalmClientBuilder` whose `build()` returns an `AsyncPalmClient`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Async;
+
+impl Default for PalmClientBuilder {
+    fn default() -> PalmClientBuilder {
+        PalmClientBuilder::new()
+    }
+}
+
+// Applies the options shared by `reqwest::ClientBuilder` and `reqwest::blocking::ClientBuilder`.
+macro_rules! apply_options {
+    ($options:expr, $builder:expr) => {{
+        let options = $options;
+        let mut builder = $builder.default_headers(options.header_map()?);
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if options.no_proxy {
+            builder = builder.no_proxy();
+        }
+        for proxy in &options.proxies {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(user_agent) = &options.user_agent {
+            builder = builder.user_agent(user_agent.as_str());
+        }
+        if let Some(max_idle) = options.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = options.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(idle_timeout);
+        }
+        // HTTP/2 is negotiated over TLS when the server supports it; let its flow control
+        // window grow with throughput so large embedding batches are not throttled.
+        builder = builder
+            .tcp_keepalive(options.tcp_keepalive)
+            .http2_adaptive_window(true);
+        #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+        {
+            for certificate in &options.root_certificates {
+                builder = builder.add_root_certificate(certificate.clone());
+            }
+            if let Some(version) = options.min_tls_version {
+                builder = builder.min_tls_version(version);
+            }
+            builder = builder.danger_accept_invalid_certs(options.accept_invalid_certs);
+        }
+        builder
+    }};
+}
+
+impl PalmClientBuilder {
+    /// Creates a builder of `PalmClient` targeting the public PaLM API endpoint.
+    pub fn new() -> PalmClientBuilder {
+        PalmClientBuilder::with_defaults()
+    }
+
+    /// Builds a blocking `PalmClient`.
+    #[cfg(feature = "blocking")]
+    pub fn build(self) -> Result<PalmClient, PalmError> {
+        let client = apply_options!(&self, reqwest::blocking::Client::builder()).build()?;
+        Ok(PalmClient {
+            config: self.config(),
+            client,
+        })
+    }
+
+    /// Builds an `AsyncPalmClient`; prefer `AsyncPalmClient::builder()`, whose `build()` does so.
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<AsyncPalmClient, PalmError> {
+        let client = apply_options!(&self, reqwest::Client::builder()).build()?;
+        Ok(AsyncPalmClient {
+            config: self.config(),
+            client,
+        })
+    }
+}
+
+impl<K> PalmClientBuilder<K> {
+    /// Creates a builder targeting the public PaLM API endpoint.
+    pub(crate) fn with_defaults() -> PalmClientBuilder<K> {
+        PalmClientBuilder {
+            kind: PhantomData,
+            auth: Auth::default(),
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            model_cache_ttl: MODEL_CACHE_TTL,
+            safety_policy: None,
+            batch_concurrency: BATCH_CONCURRENCY,
+            branch_concurrency: BRANCH_CONCURRENCY,
+            embedding_cache: None,
+            api_key_in_query: false,
+            base_url: ENDPOINT.to_string(),
+            api_version: API_VERSION.to_string(),
+            timeout: None,
+            connect_timeout: None,
+            proxies: Vec::new(),
+            no_proxy: false,
+            headers: Vec::new(),
+            user_agent: None,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            tcp_keepalive: Some(TCP_KEEPALIVE),
+            #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+            root_certificates: Vec::new(),
+            #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+            min_tls_version: None,
+            #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// Sets the PaLM API key from Google.
+    /// The key is sent in the `x-goog-api-key` header.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> PalmClientBuilder<K> {
+        self.auth = Auth::ApiKey(api_key.into());
+        self
+    }
+
+    /// Sets how requests are authenticated, replacing any API key.
+    pub fn auth(mut self, auth: Auth) -> PalmClientBuilder<K> {
+        self.auth = auth;
+        self
+    }
+
+    /// Authenticates requests with a static OAuth2 access token.
+    pub fn bearer_token(mut self, token: impl Into<String>) -> PalmClientBuilder<K> {
+        self.auth = Auth::BearerToken(token.into());
+        self
+    }
+
+    /// Authenticates requests with access tokens obtained for a service account.
+    #[cfg(feature = "service-account")]
+    pub fn service_account(mut self, account: ServiceAccount) -> PalmClientBuilder<K> {
+        self.auth = Auth::ServiceAccount(Arc::new(account));
+        self
+    }
+
+    /// Authenticates requests with access tokens returned by `provider`.
+    pub fn token_provider(
+        mut self,
+        provider: impl TokenProvider + 'static,
+    ) -> PalmClientBuilder<K> {
+        self.auth = Auth::Custom(Arc::new(provider));
+        self
+    }
+
+    /// Sends the API key in the `key` query parameter instead of the `x-goog-api-key` header.
+    ///
+    /// # Note
+    /// URLs are often logged by proxies; prefer the header unless the endpoint requires the query.
+    pub fn api_key_in_query(mut self, api_key_in_query: bool) -> PalmClientBuilder<K> {
+        self.api_key_in_query = api_key_in_query;
+        self
+    }
+
+    /// Sets the base URL of the API, e.g. a regional endpoint or a local server.
+    /// Defaults to `https://generativelanguage.googleapis.com`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> PalmClientBuilder<K> {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Sets the API version path segment.
+    /// Defaults to `v1beta2`.
+    pub fn api_version(mut self, api_version: impl Into<String>) -> PalmClientBuilder<K> {
+        self.api_version = api_version.into().trim_matches('/').to_string();
+        self
+    }
+
+    /// Sets which failed requests are retried.
+    /// Defaults to `RetryPolicy::new()`; use `RetryPolicy::none()` to disable retries.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> PalmClientBuilder<K> {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets how long models fetched with `get_model()` are cached.
+    /// Defaults to 1 hour; `Duration::ZERO` disables caching.
+    pub fn model_cache_ttl(mut self, ttl: Duration) -> PalmClientBuilder<K> {
+        self.model_cache_ttl = ttl;
+        self
+    }
+
+    /// Merges the thresholds of `safety_policy` into every `generate_text()` request.
+    /// `generateMessage` takes no safety settings, so `chat()` requests are sent unchanged.
+    pub fn safety_policy(mut self, safety_policy: SafetyPolicy) -> PalmClientBuilder<K> {
+        self.safety_policy = Some(safety_policy);
+        self
+    }
+
+    /// Sets how many requests of a `batch_embed_texts()` call are sent concurrently.
+    /// Defaults to 4.
+    pub fn batch_concurrency(mut self, batch_concurrency: usize) -> PalmClientBuilder<K> {
+        self.batch_concurrency = batch_concurrency;
+        self
+    }
+
+    /// Sets how many replies of a `branch()` call are sent concurrently.
+    /// Defaults to 4.
+    pub fn branch_concurrency(mut self, branch_concurrency: usize) -> PalmClientBuilder<K> {
+        self.branch_concurrency = branch_concurrency;
+        self
+    }
+
+    /// Answers `generate_embeddings()` and `batch_embed_texts()` from `embedding_cache` when
+    /// a text was already embedded. The cache is shared by every clone of the client.
+    pub fn embedding_cache(mut self, embedding_cache: EmbeddingCache) -> PalmClientBuilder<K> {
+        self.embedding_cache = Some(Arc::new(embedding_cache));
+        self
+    }
+
+    /// Limits requests to the per-minute quotas of `rate_limiter`.
+    /// The limiter is shared by every clone of the client.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> PalmClientBuilder<K> {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+        self
+    }
+
+    /// Sets the timeout of a whole request, from connecting until the body has been read.
+    pub fn timeout(mut self, timeout: Duration) -> PalmClientBuilder<K> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout for connecting to the server.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> PalmClientBuilder<K> {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sends requests through `proxy`.
+    /// System proxies are used unless `no_proxy` is called.
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> PalmClientBuilder<K> {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Ignores the system proxy settings.
+    pub fn no_proxy(mut self) -> PalmClientBuilder<K> {
+        self.no_proxy = true;
+        self
+    }
+
+    /// Adds a header sent with every request.
+    /// Invalid names or values are reported by `build`.
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> PalmClientBuilder<K> {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the `User-Agent` header sent with every request.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> PalmClientBuilder<K> {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Sets the maximum number of idle connections kept open per host.
+    /// Defaults to no limit.
+    pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> PalmClientBuilder<K> {
+        self.pool_max_idle_per_host = Some(max_idle);
+        self
+    }
+
+    /// Sets how long an idle connection is kept open for reuse.
+    /// Defaults to 90 seconds.
+    pub fn pool_idle_timeout(mut self, idle_timeout: Duration) -> PalmClientBuilder<K> {
+        self.pool_idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Sets the interval of TCP keep-alive probes, or disables them with `None`.
+    /// Defaults to 60 seconds.
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> PalmClientBuilder<K> {
+        self.tcp_keepalive = interval;
+        self
+    }
+
+    /// Trusts `certificate` in addition to the built-in root certificates.
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    pub fn add_root_certificate(
+        mut self,
+        certificate: reqwest::Certificate,
+    ) -> PalmClientBuilder<K> {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Sets the minimum TLS version accepted when connecting.
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    pub fn min_tls_version(mut self, version: reqwest::tls::Version) -> PalmClientBuilder<K> {
+        self.min_tls_version = Some(version);
+        self
+    }
+
+    /// Accepts invalid TLS certificates.
+    ///
+    /// # Warning
+    /// Only use this against local test servers: any certificate will be trusted.
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    pub fn danger_accept_invalid_certs(
+        mut self,
+        accept_invalid_certs: bool,
+    ) -> PalmClientBuilder<K> {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    fn header_map(&self) -> Result<HeaderMap, PalmError> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| PalmError::InvalidArgument(format!("invalid header name {}", name)))?;
+            let value = HeaderValue::from_str(value).map_err(|_| {
+                PalmError::InvalidArgument(format!("invalid value for header {}", name))
+            })?;
+            header_map.append(name, value);
+        }
+        Ok(header_map)
+    }
+
+    fn config(&self) -> Arc<ClientConfig> {
+        Arc::new(ClientConfig {
+            auth: self.auth.clone(),
+            retry_policy: self.retry_policy.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            model_cache: ModelCache::new(self.model_cache_ttl),
+            safety_policy: self.safety_policy.clone(),
+            batch_concurrency: self.batch_concurrency.max(1),
+            branch_concurrency: self.branch_concurrency.max(1),
+            embedding_cache: self.embedding_cache.clone(),
+            api_key_in_query: self.api_key_in_query,
+            base_url: self.base_url.clone(),
+            api_version: self.api_version.clone(),
+        })
+    }
+}
+
+impl PalmClientBuilder<Async> {
+    /// Builds an `AsyncPalmClient`.
+    #[cfg(feature = "async")]
+    pub fn build(self) -> Result<AsyncPalmClient, PalmError> {
+        let client = apply_options!(&self, reqwest::Client::builder()).build()?;
+        Ok(AsyncPalmClient {
+            config: self.config(),
+            client,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::PalmClientBuilder;
+    use crate::palm::PalmError;
+    use crate::retry::RetryPolicy;
+    use crate::test_server::{Reply, TestServer};
+
+    #[test]
+    fn builder_targets_custom_endpoint() {
+        let server = TestServer::start(vec![Reply::json(200, r#"{"models": []}"#)]);
+        let client = PalmClientBuilder::new()
+            .api_key("test-key")
+            .base_url(format!("{}/", server.url))
+            .api_version("v1beta3")
+            .header("x-request-source", "tests")
+            .user_agent("palm-tests/1.0")
+            .build()
+            .expect("err");
+        let models = client.list_models().expect("err");
+        assert!(models.is_empty());
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/v1beta3/models");
+        assert_eq!(request.header("x-goog-api-key"), Some("test-key"));
+        assert_eq!(request.header("x-request-source"), Some("tests"));
+        assert_eq!(request.header("user-agent"), Some("palm-tests/1.0"));
+    }
+
+    #[test]
+    fn client_is_shared_across_threads() {
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<crate::palm::PalmClient>();
+
+        let server = TestServer::start(vec![
+            Reply::json(200, r#"{"models": []}"#),
+            Reply::json(200, r#"{"models": []}"#),
+        ]);
+        let client = PalmClientBuilder::new()
+            .base_url(&server.url)
+            .pool_max_idle_per_host(4)
+            .build()
+            .expect("err");
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                let client = client.clone();
+                scope.spawn(move || client.list_models().expect("err"));
+            }
+        });
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn api_key_in_query_is_redacted() {
+        let server = TestServer::start(vec![Reply::json(
+            400,
+            r#"{"error": {"code": 400, "message": "API key secret-key not valid", "status": "INVALID_ARGUMENT"}}"#,
+        )]);
+        let client = PalmClientBuilder::new()
+            .api_key("secret-key")
+            .api_key_in_query(true)
+            .retry_policy(RetryPolicy::none())
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let err = client.list_models().unwrap_err();
+        assert_eq!(server.requests()[0].path, "/v1beta2/models?key=secret-key");
+        assert_eq!(server.requests()[0].header("x-goog-api-key"), None);
+        assert!(!err.to_string().contains("secret-key"));
+
+        // Nothing listens on the server anymore, so this fails while connecting.
+        let err = client.list_models().unwrap_err();
+        assert!(matches!(err, PalmError::Transport(_)));
+        assert!(!err.to_string().contains("secret-key"));
+        assert!(!format!("{:?}", err).contains("secret-key"));
+        assert!(
+            !format!("{:?}", PalmClientBuilder::new().api_key("secret-key")).contains("secret-key")
+        );
+    }
+
+    #[test]
+    fn builder_rejects_invalid_header() {
+        let result = PalmClientBuilder::new()
+            .header("bad header", "value")
+            .build();
+        assert!(matches!(result, Err(PalmError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,161 @@
+//! Attributing model output to the sources cited by the API.
+
+use crate::palm::{CitationMetadata, CitationSource, Message, TextCompletion};
+
+/// A segment of a candidate attributed to a source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CitedSpan<'a> {
+    /// The number of the source, starting at 1, as used by the markers of `annotate`.
+    pub number: usize,
+    /// The start of the segment in the candidate, in bytes.
+    pub start: usize,
+    /// The end of the segment in the candidate, exclusive, in bytes.
+    pub end: usize,
+    /// The attributed segment.
+    pub text: &'a str,
+    /// The source of the segment.
+    pub source: &'a CitationSource,
+}
+
+impl CitationMetadata {
+    /// Returns the segment of `text` attributed to each source, ordered by position.
+    ///
+    /// Indices are clamped to `text` and moved to the enclosing character boundaries;
+    /// a source without indices covers the whole text.
+    pub fn spans<'a>(&'a self, text: &'a str) -> Vec<CitedSpan<'a>> {
+        let mut spans: Vec<CitedSpan> = self
+            .citation_sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| {
+                let start = floor_char_boundary(text, source.start_index.map_or(0, |i| i as usize));
+                let end =
+                    ceil_char_boundary(text, source.end_index.map_or(text.len(), |i| i as usize))
+                        .max(start);
+                CitedSpan {
+                    number: index + 1,
+                    start,
+                    end,
+                    text: &text[start..end],
+                    source,
+                }
+            })
+            .collect();
+        spans.sort_by_key(|span| (span.start, span.end, span.number));
+        spans
+    }
+
+    /// Returns `text` with a marker such as `[1]` after each cited segment, numbering the
+    /// sources in the order of `citation_sources`.
+    ///
+    /// # Example
+    /// ```
+    /// let completion = &text_res.candidates.unwrap()[0];
+    /// println!("{}", completion.annotated_output());
+    /// for span in completion.citation_spans() {
+    ///     println!("[{}] {:?}", span.number, span.source.uri);
+    /// }
+    /// ```
+    pub fn annotate(&self, text: &str) -> String {
+        let mut spans = self.spans(text);
+        spans.sort_by_key(|span| (span.end, span.number));
+        let mut annotated = String::with_capacity(text.len() + spans.len() * 4);
+        let mut copied = 0;
+        for span in spans {
+            annotated.push_str(&text[copied..span.end]);
+            annotated.push_str(&format!("[{}]", span.number));
+            copied = span.end;
+        }
+        annotated.push_str(&text[copied..]);
+        annotated
+    }
+}
+
+impl TextCompletion {
+    /// Returns the segments of `output` attributed to a source, see `CitationMetadata::spans`.
+    pub fn citation_spans(&self) -> Vec<CitedSpan<'_>> {
+        self.citation_metadata
+            .as_ref()
+            .map_or_else(Vec::new, |metadata| metadata.spans(&self.output))
+    }
+
+    /// Returns `output` with citation markers, see `CitationMetadata::annotate`.
+    pub fn annotated_output(&self) -> String {
+        self.citation_metadata.as_ref().map_or_else(
+            || self.output.clone(),
+            |metadata| metadata.annotate(&self.output),
+        )
+    }
+}
+
+impl Message {
+    /// Returns the segments of `content` attributed to a source, see `CitationMetadata::spans`.
+    pub fn citation_spans(&self) -> Vec<CitedSpan<'_>> {
+        self.citation_metadata
+            .as_ref()
+            .map_or_else(Vec::new, |metadata| metadata.spans(&self.content))
+    }
+
+    /// Returns `content` with citation markers, see `CitationMetadata::annotate`.
+    pub fn annotated_content(&self) -> String {
+        self.citation_metadata.as_ref().map_or_else(
+            || self.content.clone(),
+            |metadata| metadata.annotate(&self.content),
+        )
+    }
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::palm::{Message, TextRes};
+
+    #[test]
+    fn annotates_and_splits_cited_segments() {
+        let text_res: TextRes = serde_json::from_str(
+            r#"{"candidates": [{"output": "Rust is fast. Rust is safe.", "safetyRatings": [],
+                "citationMetadata": {"citationSources": [
+                    {"startIndex": 14, "endIndex": 27, "uri": "https://example.com/safe"},
+                    {"startIndex": 0, "endIndex": 13, "uri": "https://example.com/fast", "license": "MIT"}
+                ]}}]}"#,
+        )
+        .expect("err");
+        let completion = &text_res.candidates.unwrap()[0];
+        assert_eq!(
+            completion.annotated_output(),
+            "Rust is fast.[2] Rust is safe.[1]"
+        );
+        let spans = completion.citation_spans();
+        assert_eq!(spans[0].text, "Rust is fast.");
+        assert_eq!(spans[0].number, 2);
+        assert_eq!(spans[0].source.license.as_deref(), Some("MIT"));
+        assert_eq!((spans[1].start, spans[1].end), (14, 27));
+    }
+
+    #[test]
+    fn clamps_indices_to_char_boundaries() {
+        let message: Message = serde_json::from_str(
+            r#"{"author": "1", "content": "héllo",
+                "citationMetadata": {"citationSources": [{"startIndex": 2, "endIndex": 99}]}}"#,
+        )
+        .expect("err");
+        assert_eq!(message.citation_spans()[0].text, "éllo");
+        assert_eq!(message.annotated_content(), "héllo[1]");
+        assert_eq!(Message::new("plain").annotated_content(), "plain");
+    }
+}
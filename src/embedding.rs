@@ -0,0 +1,222 @@
+//! Embeddings returned by `generate_embeddings()`, and the vector math used to compare them.
+
+use crate::palm::PalmError;
+
+/// An embedding generated by a model, stored as `f64` or, to halve its memory, as `f32`.
+///
+/// # Example
+/// ```
+/// let cat = client.generate_embeddings("embedding-gecko-001".to_string(), "cat".to_string()).expect("err");
+/// let dog = client.generate_embeddings("embedding-gecko-001".to_string(), "dog".to_string()).expect("err");
+/// println!("{}", cat.cosine_similarity(&dog).expect("err"));
+/// let compact = cat.into_f32().normalize();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embedding {
+    model: String,
+    values: Values,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Values {
+    F64(Vec<f64>),
+    F32(Vec<f32>),
+}
+
+impl Embedding {
+    /// Creates an embedding generated by `model`.
+    pub fn new(model: impl Into<String>, values: Vec<f64>) -> Embedding {
+        Embedding {
+            model: model.into(),
+            values: Values::F64(values),
+        }
+    }
+
+    /// Creates an embedding generated by `model`, stored as `f32`.
+    pub fn from_f32(model: impl Into<String>, values: Vec<f32>) -> Embedding {
+        Embedding {
+            model: model.into(),
+            values: Values::F32(values),
+        }
+    }
+
+    /// Returns the model that generated the embedding.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Returns the number of values of the embedding.
+    pub fn dimension(&self) -> usize {
+        match &self.values {
+            Values::F64(values) => values.len(),
+            Values::F32(values) => values.len(),
+        }
+    }
+
+    /// Returns whether the values are stored as `f32`.
+    pub fn is_f32(&self) -> bool {
+        matches!(self.values, Values::F32(_))
+    }
+
+    /// Returns the values stored as `f64`, or `None` if they are stored as `f32`.
+    pub fn as_f64(&self) -> Option<&[f64]> {
+        match &self.values {
+            Values::F64(values) => Some(values),
+            Values::F32(_) => None,
+        }
+    }
+
+    /// Returns the values stored as `f32`, or `None` if they are stored as `f64`.
+    pub fn as_f32(&self) -> Option<&[f32]> {
+        match &self.values {
+            Values::F64(_) => None,
+            Values::F32(values) => Some(values),
+        }
+    }
+
+    /// Returns an iterator over the values.
+    pub fn iter(&self) -> impl Iterator<Item = f64> + '_ {
+        let (f64s, f32s): (&[f64], &[f32]) = match &self.values {
+            Values::F64(values) => (values, &[]),
+            Values::F32(values) => (&[], values),
+        };
+        f64s.iter()
+            .copied()
+            .chain(f32s.iter().map(|&value| f64::from(value)))
+    }
+
+    /// Returns the values as `f64`.
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.iter().collect()
+    }
+
+    /// Stores the values as `f32`, halving the memory of the embedding.
+    pub fn into_f32(self) -> Embedding {
+        let values = match self.values {
+            Values::F64(values) => {
+                Values::F32(values.into_iter().map(|value| value as f32).collect())
+            }
+            values => values,
+        };
+        Embedding {
+            model: self.model,
+            values,
+        }
+    }
+
+    /// Stores the values as `f64`.
+    pub fn into_f64(self) -> Embedding {
+        let values = match self.values {
+            Values::F32(values) => Values::F64(values.into_iter().map(f64::from).collect()),
+            values => values,
+        };
+        Embedding {
+            model: self.model,
+            values,
+        }
+    }
+
+    /// Returns the L2 norm of the embedding.
+    pub fn norm(&self) -> f64 {
+        self.iter().map(|value| value * value).sum::<f64>().sqrt()
+    }
+
+    /// Scales the embedding to a norm of 1, keeping how it is stored.
+    /// An embedding whose values are all zero is returned unchanged.
+    pub fn normalize(mut self) -> Embedding {
+        let norm = self.norm();
+        if norm == 0.0 {
+            return self;
+        }
+        match &mut self.values {
+            Values::F64(values) => values.iter_mut().for_each(|value| *value /= norm),
+            Values::F32(values) => values
+                .iter_mut()
+                .for_each(|value| *value = (f64::from(*value) / norm) as f32),
+        }
+        self
+    }
+
+    /// Returns the dot product of two embeddings.
+    ///
+    /// Returns `PalmError::InvalidArgument` if their dimensions differ.
+    pub fn dot(&self, other: &Embedding) -> Result<f64, PalmError> {
+        self.check_dimension(other)?;
+        Ok(self.dot_unchecked(other))
+    }
+
+    /// Returns the cosine similarity of two embeddings, between -1 and 1,
+    /// or 0 if the values of either are all zero.
+    ///
+    /// Returns `PalmError::InvalidArgument` if their dimensions differ.
+    pub fn cosine_similarity(&self, other: &Embedding) -> Result<f64, PalmError> {
+        self.check_dimension(other)?;
+        let norms = self.norm() * other.norm();
+        if norms == 0.0 {
+            return Ok(0.0);
+        }
+        Ok(self.dot_unchecked(other) / norms)
+    }
+
+    /// Returns the Euclidean distance between two embeddings.
+    ///
+    /// Returns `PalmError::InvalidArgument` if their dimensions differ.
+    pub fn euclidean_distance(&self, other: &Embedding) -> Result<f64, PalmError> {
+        self.check_dimension(other)?;
+        Ok(self
+            .iter()
+            .zip(other.iter())
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt())
+    }
+
+    pub(crate) fn dot_unchecked(&self, other: &Embedding) -> f64 {
+        self.iter().zip(other.iter()).map(|(a, b)| a * b).sum()
+    }
+
+    fn check_dimension(&self, other: &Embedding) -> Result<(), PalmError> {
+        if self.dimension() != other.dimension() {
+            return Err(PalmError::InvalidArgument(format!(
+                "embeddings of dimension {} and {} cannot be compared",
+                self.dimension(),
+                other.dimension()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Embedding;
+    use crate::palm::PalmError;
+
+    #[test]
+    fn compares_embeddings() {
+        let a = Embedding::new("embedding-gecko-001", vec![3.0, 4.0]);
+        let b = Embedding::new("embedding-gecko-001", vec![4.0, 3.0]);
+        assert_eq!(a.dot(&b).expect("err"), 24.0);
+        assert_eq!(a.norm(), 5.0);
+        assert!((a.cosine_similarity(&b).expect("err") - 0.96).abs() < 1e-12);
+        assert!((a.euclidean_distance(&b).expect("err") - 2f64.sqrt()).abs() < 1e-12);
+        let zero = Embedding::new("embedding-gecko-001", vec![0.0, 0.0]);
+        assert_eq!(a.cosine_similarity(&zero).expect("err"), 0.0);
+        assert!(matches!(
+            a.dot(&Embedding::new("embedding-gecko-001", vec![1.0])),
+            Err(PalmError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn normalizes_and_converts_storage() {
+        let embedding = Embedding::new("embedding-gecko-001", vec![3.0, 4.0]).normalize();
+        assert_eq!(embedding.as_f64(), Some(&[0.6, 0.8][..]));
+        let compact = embedding.clone().into_f32();
+        assert!(compact.is_f32());
+        assert_eq!(compact.dimension(), 2);
+        assert_eq!(compact.model(), "embedding-gecko-001");
+        assert!((compact.cosine_similarity(&embedding).expect("err") - 1.0).abs() < 1e-6);
+        assert!(!compact.into_f64().is_f32());
+    }
+}
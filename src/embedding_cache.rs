@@ -0,0 +1,519 @@
+//! Caching embeddings, so that a text is embedded once per model instead of once per request.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::checksum::fnv1a;
+use crate::embedding::Embedding;
+use crate::palm::PalmError;
+
+// Approximate memory used by a cached embedding besides its text and values.
+const ENTRY_OVERHEAD: usize = 64;
+const FILE_EXTENSION: &str = "emb";
+
+/// Embeddings kept in memory, least recently used first evicted, and optionally in a directory.
+///
+/// Set on a client with `PalmClientBuilder::embedding_cache`, the cache answers
+/// `generate_embeddings()` and `batch_embed_texts()` for texts already embedded by the same model.
+/// Entries are keyed by model name and a hash of the text, and the text is compared on lookup.
+/// `AsyncPalmClient` reads and writes the cache from the blocking thread pool of the runtime.
+///
+/// # Example
+/// ```
+/// use palm_api::embedding_cache::EmbeddingCache;
+///
+/// let cache = EmbeddingCache::new(64 * 1024 * 1024)
+///     .with_directory("embeddings")
+///     .expect("err")
+///     .max_disk_bytes(1024 * 1024 * 1024);
+/// let client = palm_api::palm::PalmClient::builder()
+///     .api_key(API_KEY)
+///     .embedding_cache(cache)
+///     .build()
+///     .expect("err");
+/// client.generate_embeddings("embedding-gecko-001".to_string(), "hello".to_string()).expect("err");
+/// println!("{:?}", client.embedding_cache().unwrap().stats());
+/// ```
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    max_bytes: usize,
+    memory: Mutex<Memory>,
+    disk: Option<Disk>,
+}
+
+/// Counters of an `EmbeddingCache`, returned by `EmbeddingCache::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Lookups answered by the cache, from memory or from disk.
+    pub hits: u64,
+    /// Lookups answered from disk, included in `hits`.
+    pub disk_hits: u64,
+    /// Lookups the cache could not answer.
+    pub misses: u64,
+    /// Entries evicted from memory or from disk to respect the size limits.
+    pub evictions: u64,
+    /// Entries in memory.
+    pub entries: usize,
+    /// Approximate bytes used in memory.
+    pub bytes: usize,
+    /// Bytes of the files on disk.
+    pub disk_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    model: String,
+    hash: u64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    text: String,
+    values: Vec<f64>,
+    used: u64,
+    size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Memory {
+    entries: HashMap<Key, Cached>,
+    // Keys by last use, least recent first.
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct Disk {
+    directory: PathBuf,
+    max_bytes: Option<u64>,
+    files: Mutex<Files>,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    // File sizes by write time, oldest first.
+    order: BTreeMap<(SystemTime, String), u64>,
+    written: HashMap<String, SystemTime>,
+    bytes: u64,
+}
+
+impl EmbeddingCache {
+    /// Creates a cache keeping up to about `max_bytes` of embeddings in memory.
+    pub fn new(max_bytes: usize) -> EmbeddingCache {
+        EmbeddingCache {
+            max_bytes,
+            memory: Mutex::new(Memory::default()),
+            disk: None,
+        }
+    }
+
+    /// Also keeps embeddings in `directory`, one file per text, so that they outlive the process.
+    ///
+    /// The directory is created if missing. Errors reading or writing its files are treated as
+    /// cache misses.
+    pub fn with_directory(
+        mut self,
+        directory: impl AsRef<Path>,
+    ) -> Result<EmbeddingCache, PalmError> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        let mut files = Files::default();
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(FILE_EXTENSION) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.add(
+                entry.file_name().to_string_lossy().into_owned(),
+                modified,
+                metadata.len(),
+            );
+        }
+        self.disk = Some(Disk {
+            directory,
+            max_bytes: None,
+            files: Mutex::new(files),
+        });
+        Ok(self)
+    }
+
+    /// Limits the files of the directory to about `max_bytes`, removing the oldest first.
+    /// Does nothing without `with_directory`.
+    pub fn max_disk_bytes(mut self, max_bytes: u64) -> EmbeddingCache {
+        if let Some(disk) = &mut self.disk {
+            disk.max_bytes = Some(max_bytes);
+        }
+        self
+    }
+
+    /// Returns the counters of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = self.lock().stats;
+        if let Some(disk) = &self.disk {
+            stats.disk_bytes = disk.lock().bytes;
+        }
+        stats
+    }
+
+    /// Returns the embedding of `text` by `model`, if cached.
+    pub fn get(&self, model: &str, text: &str) -> Option<Embedding> {
+        let key = Key::new(model, text);
+        let mut memory = self.lock();
+        if let Some(values) = memory.touch(&key, text) {
+            memory.stats.hits += 1;
+            return Some(Embedding::new(model, values));
+        }
+        drop(memory);
+        let values = self.disk.as_ref().and_then(|disk| disk.read(&key, text));
+        let mut memory = self.lock();
+        match values {
+            Some(values) => {
+                memory.stats.hits += 1;
+                memory.stats.disk_hits += 1;
+                memory.insert(key, text, values.clone(), self.max_bytes);
+                Some(Embedding::new(model, values))
+            }
+            None => {
+                memory.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches `embedding` as the embedding of `text` by its model.
+    pub fn insert(&self, text: &str, embedding: &Embedding) {
+        let key = Key::new(embedding.model(), text);
+        let values = embedding.to_vec();
+        if let Some(disk) = &self.disk {
+            let evicted = disk.write(&key, text, &values);
+            self.lock().stats.evictions += evicted;
+        }
+        self.lock().insert(key, text, values, self.max_bytes);
+    }
+
+    /// Removes every embedding from memory and from the directory, keeping the counters.
+    pub fn clear(&self) {
+        let mut memory = self.lock();
+        memory.entries.clear();
+        memory.order.clear();
+        memory.stats.entries = 0;
+        memory.stats.bytes = 0;
+        if let Some(disk) = &self.disk {
+            let mut files = disk.lock();
+            for (_, name) in std::mem::take(&mut files.order).into_keys() {
+                let _ = std::fs::remove_file(disk.directory.join(name));
+            }
+            *files = Files::default();
+        }
+    }
+
+    /// Splits `texts` into the embeddings found in the cache, in input order, and the texts to embed.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn split(
+        &self,
+        model: &str,
+        texts: Vec<String>,
+    ) -> (Vec<Option<Embedding>>, Vec<String>) {
+        let mut misses = Vec::new();
+        let cached = texts
+            .into_iter()
+            .map(|text| {
+                let embedding = self.get(model, &text);
+                if embedding.is_none() {
+                    misses.push(text);
+                }
+                embedding
+            })
+            .collect();
+        (cached, misses)
+    }
+
+    /// Caches the embeddings of `texts`, and fills the gaps of `cached` with them.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn merge(
+        &self,
+        cached: Vec<Option<Embedding>>,
+        texts: &[String],
+        embedded: Vec<Result<Embedding, PalmError>>,
+    ) -> Vec<Result<Embedding, PalmError>> {
+        for (text, embedding) in texts.iter().zip(&embedded) {
+            if let Ok(embedding) = embedding {
+                self.insert(text, embedding);
+            }
+        }
+        let mut embedded = embedded.into_iter();
+        cached
+            .into_iter()
+            .filter_map(|embedding| match embedding {
+                Some(embedding) => Some(Ok(embedding)),
+                None => embedded.next(),
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Memory> {
+        self.memory.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Key {
+    fn new(model: &str, text: &str) -> Key {
+        let model = model.strip_prefix("models/").unwrap_or(model);
+        Key {
+            model: model.to_string(),
+            hash: fnv1a(text.as_bytes()),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let mut bytes = self.model.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.hash.to_le_bytes());
+        format!("{:016x}.{}", fnv1a(&bytes), FILE_EXTENSION)
+    }
+}
+
+impl Memory {
+    // Returns the values of `key` and marks them as just used, if they embed `text`.
+    fn touch(&mut self, key: &Key, text: &str) -> Option<Vec<f64>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let cached = self
+            .entries
+            .get_mut(key)
+            .filter(|cached| cached.text == text)?;
+        self.order.remove(&cached.used);
+        self.order.insert(tick, key.clone());
+        cached.used = tick;
+        Some(cached.values.clone())
+    }
+
+    fn insert(&mut self, key: Key, text: &str, values: Vec<f64>, max_bytes: usize) {
+        self.tick += 1;
+        let size = ENTRY_OVERHEAD + key.model.len() + text.len() + values.len() * 8;
+        if let Some(previous) = self.entries.remove(&key) {
+            self.order.remove(&previous.used);
+            self.stats.bytes -= previous.size;
+        }
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Cached {
+                text: text.to_string(),
+                values,
+                used: self.tick,
+                size,
+            },
+        );
+        self.stats.bytes += size;
+        while self.stats.bytes > max_bytes {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&key) {
+                self.stats.bytes -= evicted.size;
+                self.stats.evictions += 1;
+            }
+        }
+        self.stats.entries = self.entries.len();
+    }
+}
+
+impl Disk {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Files> {
+        self.files.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // File layout: the model length (u64), the model, the text length (u64), the text, then
+    // the values (f64), all little-endian. The model and text are compared on read, as file
+    // names are hashes that may collide.
+    fn read(&self, key: &Key, text: &str) -> Option<Vec<f64>> {
+        let bytes = std::fs::read(self.directory.join(key.file_name())).ok()?;
+        let (model, rest) = split_prefixed(&bytes)?;
+        let (stored_text, values) = split_prefixed(rest)?;
+        if model != key.model.as_bytes() || stored_text != text.as_bytes() || values.len() % 8 != 0
+        {
+            return None;
+        }
+        Some(
+            values
+                .chunks_exact(8)
+                .map(|value| f64::from_le_bytes(value.try_into().unwrap_or_default()))
+                .collect(),
+        )
+    }
+
+    // Writes the file of `key`, and returns the number of files evicted.
+    fn write(&self, key: &Key, text: &str, values: &[f64]) -> u64 {
+        let mut bytes = Vec::with_capacity(16 + key.model.len() + text.len() + values.len() * 8);
+        bytes.extend_from_slice(&(key.model.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(key.model.as_bytes());
+        bytes.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+        values
+            .iter()
+            .for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+        let name = key.file_name();
+        let path = self.directory.join(&name);
+        let temp = self.directory.join(format!("{}.tmp", name));
+        if std::fs::write(&temp, &bytes).is_err() || std::fs::rename(&temp, &path).is_err() {
+            let _ = std::fs::remove_file(&temp);
+            return 0;
+        }
+        let mut files = self.lock();
+        files.add(name, SystemTime::now(), bytes.len() as u64);
+        let mut evicted = 0;
+        while self
+            .max_bytes
+            .is_some_and(|max_bytes| files.bytes > max_bytes)
+        {
+            let Some(((_, name), size)) = files.order.pop_first() else {
+                break;
+            };
+            files.written.remove(&name);
+            files.bytes -= size;
+            let _ = std::fs::remove_file(self.directory.join(name));
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+impl Files {
+    fn add(&mut self, name: String, written: SystemTime, size: u64) {
+        if let Some(previous) = self.written.remove(&name) {
+            if let Some(previous_size) = self.order.remove(&(previous, name.clone())) {
+                self.bytes -= previous_size;
+            }
+        }
+        self.written.insert(name.clone(), written);
+        self.order.insert((written, name), size);
+        self.bytes += size;
+    }
+}
+
+// Splits the bytes prefixed with their length (u64) off the start of `bytes`.
+fn split_prefixed(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = bytes.split_first_chunk::<8>()?;
+    let len = usize::try_from(u64::from_le_bytes(*len)).ok()?;
+    (len <= rest.len()).then(|| rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmbeddingCache, Key};
+    use crate::embedding::Embedding;
+
+    const MODEL: &str = "embedding-gecko-001";
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // Room for two entries of one value and a 1-byte text.
+        let cache = EmbeddingCache::new(2 * (64 + MODEL.len() + 1 + 8));
+        cache.insert("a", &Embedding::new(MODEL, vec![1.0]));
+        cache.insert("b", &Embedding::new(MODEL, vec![2.0]));
+        assert!(cache.get(&format!("models/{}", MODEL), "a").is_some());
+        cache.insert("c", &Embedding::new(MODEL, vec![3.0]));
+        assert!(cache.get(MODEL, "b").is_none());
+        assert_eq!(cache.get(MODEL, "a").expect("err").to_vec(), vec![1.0]);
+        assert_eq!(cache.get(MODEL, "c").expect("err").model(), MODEL);
+        assert!(cache.get("other-model", "c").is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+    }
+
+    #[test]
+    fn keeps_embeddings_on_disk() {
+        let directory =
+            std::env::temp_dir().join(format!("palm_api-embeddings-{}", std::process::id()));
+        let cache = EmbeddingCache::new(1024)
+            .with_directory(&directory)
+            .expect("err");
+        cache.insert("hello", &Embedding::new(MODEL, vec![0.5, 0.25]));
+        cache.insert("world", &Embedding::new(MODEL, vec![0.75, 0.5]));
+        drop(cache);
+
+        // Each file holds the model and text with their lengths and two values:
+        // 8 + 19 + 8 + 5 + 16 bytes.
+        let cache = EmbeddingCache::new(1024)
+            .with_directory(&directory)
+            .expect("err")
+            .max_disk_bytes(2 * 56);
+        assert_eq!(cache.stats().disk_bytes, 2 * 56);
+        let embedding = cache.get(MODEL, "hello").expect("err");
+        assert_eq!(embedding.to_vec(), vec![0.5, 0.25]);
+        assert_eq!(cache.stats().disk_hits, 1);
+        cache.insert("other", &Embedding::new(MODEL, vec![1.0, 0.0]));
+        assert_eq!(cache.stats().disk_bytes, 2 * 56);
+        assert_eq!(cache.stats().evictions, 1);
+
+        cache.clear();
+        assert_eq!(cache.stats().disk_bytes, 0);
+        assert!(cache.get(MODEL, "other").is_none());
+        std::fs::remove_dir_all(&directory).expect("err");
+    }
+
+    #[test]
+    fn disk_files_of_another_model_are_misses() {
+        let directory =
+            std::env::temp_dir().join(format!("palm_api-models-{}", std::process::id()));
+        let cache = EmbeddingCache::new(0)
+            .with_directory(&directory)
+            .expect("err");
+        cache.insert("hello", &Embedding::new(MODEL, vec![0.5]));
+        // Stands for a hash collision between the file names of two models.
+        let other = Key::new("other-model", "hello");
+        std::fs::copy(
+            directory.join(Key::new(MODEL, "hello").file_name()),
+            directory.join(other.file_name()),
+        )
+        .expect("err");
+        assert!(cache.get("other-model", "hello").is_none());
+        assert!(cache.get(MODEL, "hello").is_some());
+        std::fs::remove_dir_all(&directory).expect("err");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn client_embeds_cache_misses_only() {
+        use crate::palm::PalmClient;
+        use crate::test_server::{Reply, TestServer};
+
+        let server = TestServer::start(vec![
+            Reply::json(200, r#"{"embedding": {"value": [0.5]}}"#),
+            Reply::json(200, r#"{"embeddings": [{"value": [0.25]}]}"#),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .embedding_cache(EmbeddingCache::new(1024 * 1024))
+            .build()
+            .expect("err");
+        client
+            .generate_embeddings(MODEL.to_string(), "cached".to_string())
+            .expect("err");
+        let results = client.batch_embed_texts(
+            MODEL.to_string(),
+            vec!["cached".to_string(), "new".to_string()],
+        );
+        assert_eq!(results[0].as_ref().expect("err").to_vec(), vec![0.5]);
+        assert_eq!(results[1].as_ref().expect("err").to_vec(), vec![0.25]);
+        let embedding = client
+            .generate_embeddings(MODEL.to_string(), "new".to_string())
+            .expect("err");
+        assert_eq!(embedding.to_vec(), vec![0.25]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).expect("err");
+        assert_eq!(body["texts"], serde_json::json!(["new"]));
+        let stats = client.embedding_cache().expect("err").stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+}
@@ -0,0 +1,495 @@
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Errors returned by `PalmClient`.
+///
+/// Errors caused by a non-successful HTTP status carry the `ApiError` sent back by the server.
+///
+/// # Example
+/// ```
+/// match client.get_model("text-bison-001".to_string()) {
+///     Ok(model) => println!("{}", model.description),
+///     Err(palm_api::PalmError::NotFound(api_error)) => println!("{}", api_error.message),
+///     Err(err) => return Err(err.into()),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum PalmError {
+    /// The API key is invalid or lacks permission (HTTP 401 or 403).
+    Unauthorized(ApiError),
+    /// The token endpoint of a service account did not issue an access token.
+    Auth(ApiError),
+    /// The requested model or resource does not exist (HTTP 404).
+    NotFound(ApiError),
+    /// The request was rejected by the server (HTTP 400).
+    BadRequest(ApiError),
+    /// The quota for the API key has been exhausted (HTTP 429).
+    RateLimited(ApiError),
+    /// The server failed to process the request, or answered with an unexpected status.
+    ServerError(ApiError),
+    /// The request could not be sent or the response could not be read.
+    Transport(reqwest::Error),
+    /// The response body could not be decoded.
+    Decode(serde_json::Error),
+    /// An argument was rejected before the request was sent.
+    InvalidArgument(String),
+    /// `reply()` was given the index of a candidate the response does not have.
+    MissingCandidate {
+        /// The requested candidate index.
+        index: usize,
+        /// The number of candidates in the response.
+        available: usize,
+    },
+    /// A request embedding several texts of `batch_embed_texts()` failed with this error,
+    /// shared by every text of the request.
+    BatchFailed(Arc<PalmError>),
+    /// A file could not be read or written.
+    Io(std::io::Error),
+    /// A file is not an index saved by `VectorStore::save`, or was corrupted.
+    CorruptIndex(String),
+}
+
+impl PalmError {
+    /// Builds the error matching a non-successful HTTP status.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn from_response(status: reqwest::StatusCode, body: &str) -> PalmError {
+        let api_error = ApiError::from_body(status, body);
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                PalmError::Unauthorized(api_error)
+            }
+            reqwest::StatusCode::NOT_FOUND => PalmError::NotFound(api_error),
+            reqwest::StatusCode::BAD_REQUEST => PalmError::BadRequest(api_error),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => PalmError::RateLimited(api_error),
+            _ => PalmError::ServerError(api_error),
+        }
+    }
+
+    /// Replaces every occurrence of `secret` in the error with `REDACTED`.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn redact(mut self, secret: &str) -> PalmError {
+        if secret.is_empty() {
+            return self;
+        }
+        if let PalmError::Transport(err) = &mut self {
+            if let Some(url) = err.url_mut() {
+                if url.as_str().contains(secret) {
+                    if let Ok(redacted) =
+                        reqwest::Url::parse(&url.as_str().replace(secret, "REDACTED"))
+                    {
+                        *url = redacted;
+                    }
+                }
+            }
+        }
+        match &mut self {
+            PalmError::Unauthorized(api_error)
+            | PalmError::Auth(api_error)
+            | PalmError::NotFound(api_error)
+            | PalmError::BadRequest(api_error)
+            | PalmError::RateLimited(api_error)
+            | PalmError::ServerError(api_error) => api_error.redact(secret),
+            PalmError::InvalidArgument(message) => redact_string(message, secret),
+            _ => {}
+        }
+        self
+    }
+
+    /// Returns the error sent back by the server, if the request reached it.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            PalmError::Unauthorized(api_error)
+            | PalmError::Auth(api_error)
+            | PalmError::NotFound(api_error)
+            | PalmError::BadRequest(api_error)
+            | PalmError::RateLimited(api_error)
+            | PalmError::ServerError(api_error) => Some(api_error),
+            PalmError::BatchFailed(err) => err.api_error(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PalmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PalmError::Unauthorized(api_error) => write!(f, "unauthorized: {}", api_error),
+            PalmError::Auth(api_error) => write!(f, "authentication failed: {}", api_error),
+            PalmError::NotFound(api_error) => write!(f, "not found: {}", api_error),
+            PalmError::BadRequest(api_error) => write!(f, "bad request: {}", api_error),
+            PalmError::RateLimited(api_error) => write!(f, "rate limited: {}", api_error),
+            PalmError::ServerError(api_error) => write!(f, "server error: {}", api_error),
+            PalmError::Transport(err) => write!(f, "transport error: {}", err),
+            PalmError::Decode(err) => write!(f, "failed to decode response: {}", err),
+            PalmError::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            PalmError::MissingCandidate { index, available } => write!(
+                f,
+                "no candidate at index {}, the response has {}",
+                index, available
+            ),
+            PalmError::BatchFailed(err) => write!(f, "batch request failed: {}", err),
+            PalmError::Io(err) => write!(f, "I/O error: {}", err),
+            PalmError::CorruptIndex(message) => write!(f, "corrupt index file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PalmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PalmError::Transport(err) => Some(err),
+            PalmError::Decode(err) => Some(err),
+            PalmError::BatchFailed(err) => Some(err.as_ref()),
+            PalmError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for PalmError {
+    fn from(err: reqwest::Error) -> PalmError {
+        PalmError::Transport(err)
+    }
+}
+
+impl From<std::io::Error> for PalmError {
+    fn from(err: std::io::Error) -> PalmError {
+        PalmError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PalmError {
+    fn from(err: serde_json::Error) -> PalmError {
+        PalmError::Decode(err)
+    }
+}
+
+/// The error returned by Google APIs in the body of a failed request.
+///
+/// # Format
+/// `{"error": {"code": 400, "message": "...", "status": "INVALID_ARGUMENT", "details": [...]}}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    /// The HTTP status code of the response.
+    #[serde(default)]
+    pub code: u16,
+    /// A developer-facing error message.
+    #[serde(default)]
+    pub message: String,
+    /// The canonical error code, such as INVALID_ARGUMENT or RESOURCE_EXHAUSTED.
+    #[serde(default)]
+    pub status: String,
+    /// Additional details about the error.
+    #[serde(default)]
+    pub details: Vec<ErrorDetail>,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    error: ApiError,
+}
+
+impl ApiError {
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    // Falls back to the raw body when the server did not send the usual envelope.
+    pub(crate) fn from_body(status: reqwest::StatusCode, body: &str) -> ApiError {
+        match serde_json::from_str::<ErrorEnvelope>(body) {
+            Ok(envelope) => envelope.error,
+            Err(_) => ApiError {
+                code: status.as_u16(),
+                message: body.trim().to_string(),
+                status: status.canonical_reason().unwrap_or_default().to_string(),
+                details: Vec::new(),
+            },
+        }
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn redact(&mut self, secret: &str) {
+        redact_string(&mut self.message, secret);
+        for detail in &mut self.details {
+            match detail {
+                ErrorDetail::ErrorInfo(info) => {
+                    redact_string(&mut info.reason, secret);
+                    redact_string(&mut info.domain, secret);
+                    info.metadata = std::mem::take(&mut info.metadata)
+                        .into_iter()
+                        .map(|(mut key, mut value)| {
+                            redact_string(&mut key, secret);
+                            redact_string(&mut value, secret);
+                            (key, value)
+                        })
+                        .collect();
+                }
+                ErrorDetail::BadRequest(bad_request) => {
+                    for violation in &mut bad_request.field_violations {
+                        redact_string(&mut violation.field, secret);
+                        redact_string(&mut violation.description, secret);
+                    }
+                }
+                ErrorDetail::RetryInfo(_) => {}
+                ErrorDetail::Other(value) => redact_value(value, secret),
+            }
+        }
+    }
+
+    /// Returns the `google.rpc.ErrorInfo` detail, if any.
+    pub fn error_info(&self) -> Option<&ErrorInfo> {
+        self.details.iter().find_map(|detail| match detail {
+            ErrorDetail::ErrorInfo(info) => Some(info),
+            _ => None,
+        })
+    }
+
+    /// Returns the fields rejected by the server, as listed in the `google.rpc.BadRequest` details.
+    pub fn field_violations(&self) -> Vec<&FieldViolation> {
+        self.details
+            .iter()
+            .filter_map(|detail| match detail {
+                ErrorDetail::BadRequest(bad_request) => Some(&bad_request.field_violations),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Returns the `google.rpc.RetryInfo` detail, if any.
+    pub fn retry_info(&self) -> Option<&RetryInfo> {
+        self.details.iter().find_map(|detail| match detail {
+            ErrorDetail::RetryInfo(info) => Some(info),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.status.is_empty() {
+            write!(f, " ({} {})", self.code, self.status)?;
+        }
+        for violation in self.field_violations() {
+            write!(f, "; {}: {}", violation.field, violation.description)?;
+        }
+        Ok(())
+    }
+}
+
+const ERROR_INFO_TYPE: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+const BAD_REQUEST_TYPE: &str = "type.googleapis.com/google.rpc.BadRequest";
+const RETRY_INFO_TYPE: &str = "type.googleapis.com/google.rpc.RetryInfo";
+
+/// A detail attached to an `ApiError`, selected by its `@type` field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorDetail {
+    /// `google.rpc.ErrorInfo`: the reason of the error.
+    ErrorInfo(ErrorInfo),
+    /// `google.rpc.BadRequest`: the fields of the request that were rejected.
+    BadRequest(BadRequest),
+    /// `google.rpc.RetryInfo`: when the request can be retried.
+    RetryInfo(RetryInfo),
+    /// Any other detail, kept as raw JSON.
+    Other(serde_json::Value),
+}
+
+impl<'de> Deserialize<'de> for ErrorDetail {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ErrorDetail, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let detail = match value["@type"].as_str() {
+            Some(ERROR_INFO_TYPE) => serde_json::from_value(value).map(ErrorDetail::ErrorInfo),
+            Some(BAD_REQUEST_TYPE) => serde_json::from_value(value).map(ErrorDetail::BadRequest),
+            Some(RETRY_INFO_TYPE) => serde_json::from_value(value).map(ErrorDetail::RetryInfo),
+            _ => Ok(ErrorDetail::Other(value)),
+        };
+        detail.map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ErrorDetail {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (type_url, value) = match self {
+            ErrorDetail::ErrorInfo(info) => (ERROR_INFO_TYPE, serde_json::to_value(info)),
+            ErrorDetail::BadRequest(bad_request) => {
+                (BAD_REQUEST_TYPE, serde_json::to_value(bad_request))
+            }
+            ErrorDetail::RetryInfo(info) => (RETRY_INFO_TYPE, serde_json::to_value(info)),
+            ErrorDetail::Other(value) => return value.serialize(serializer),
+        };
+        let mut value = value.map_err(ser::Error::custom)?;
+        value["@type"] = serde_json::Value::from(type_url);
+        value.serialize(serializer)
+    }
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn redact_string(string: &mut String, secret: &str) {
+    if string.contains(secret) {
+        *string = string.replace(secret, "REDACTED");
+    }
+}
+
+// Redacts every string of a detail the crate does not know, keys included.
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn redact_value(value: &mut serde_json::Value, secret: &str) {
+    match value {
+        serde_json::Value::String(string) => redact_string(string, secret),
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| redact_value(value, secret)),
+        serde_json::Value::Object(object) => {
+            *object = std::mem::take(object)
+                .into_iter()
+                .map(|(mut key, mut value)| {
+                    redact_string(&mut key, secret);
+                    redact_value(&mut value, secret);
+                    (key, value)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
+/// Describes the cause of the error with structured details.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ErrorInfo {
+    /// The reason of the error, such as API_KEY_INVALID.
+    pub reason: String,
+    /// The logical grouping to which the reason belongs, such as googleapis.com.
+    pub domain: String,
+    /// Additional structured details about the error.
+    pub metadata: HashMap<String, String>,
+}
+
+/// Describes violations in a client request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BadRequest {
+    /// Describes all violations in a client request.
+    pub field_violations: Vec<FieldViolation>,
+}
+
+/// A single bad request field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct FieldViolation {
+    /// A path to the field in the request body, such as `prompt.text`.
+    pub field: String,
+    /// A description of why the request element is bad.
+    pub description: String,
+}
+
+/// Describes when the client may retry a failed request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetryInfo {
+    /// Clients should wait at least this long before retrying, e.g. "1.5s".
+    pub retry_delay: Option<String>,
+}
+
+impl RetryInfo {
+    /// Parses `retry_delay` into a `Duration`.
+    pub fn delay(&self) -> Option<Duration> {
+        let seconds: f64 = self.retry_delay.as_ref()?.strip_suffix('s')?.parse().ok()?;
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+}
+
+#[cfg(all(test, any(feature = "blocking", feature = "async")))]
+mod tests {
+    use super::{ErrorDetail, PalmError};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn from_response_maps_status() {
+        assert!(matches!(
+            PalmError::from_response(StatusCode::FORBIDDEN, ""),
+            PalmError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            PalmError::from_response(StatusCode::TOO_MANY_REQUESTS, ""),
+            PalmError::RateLimited(_)
+        ));
+        match PalmError::from_response(StatusCode::BAD_GATEWAY, "upstream down") {
+            PalmError::ServerError(api_error) => {
+                assert_eq!(api_error.code, 502);
+                assert_eq!(api_error.message, "upstream down");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_response_parses_details() {
+        let body = r#"{
+            "error": {
+                "code": 400,
+                "message": "Invalid value at 'temperature'",
+                "status": "INVALID_ARGUMENT",
+                "details": [
+                    {
+                        "@type": "type.googleapis.com/google.rpc.BadRequest",
+                        "fieldViolations": [
+                            {"field": "temperature", "description": "must be in [0, 1]"}
+                        ]
+                    },
+                    {
+                        "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                        "reason": "API_KEY_INVALID",
+                        "domain": "googleapis.com",
+                        "metadata": {"service": "generativelanguage.googleapis.com"}
+                    },
+                    {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "1.5s"},
+                    {"@type": "type.googleapis.com/google.rpc.Help", "links": []}
+                ]
+            }
+        }"#;
+        let err = PalmError::from_response(StatusCode::BAD_REQUEST, body);
+        let api_error = err.api_error().expect("api error");
+        assert_eq!(api_error.status, "INVALID_ARGUMENT");
+        assert_eq!(api_error.field_violations()[0].field, "temperature");
+        assert_eq!(api_error.error_info().unwrap().reason, "API_KEY_INVALID");
+        assert_eq!(
+            api_error.retry_info().unwrap().delay(),
+            Some(Duration::from_millis(1500))
+        );
+        assert!(matches!(api_error.details[3], ErrorDetail::Other(_)));
+        assert!(err.to_string().contains("temperature: must be in [0, 1]"));
+
+        let round_trip: super::ApiError =
+            serde_json::from_value(serde_json::to_value(api_error).unwrap()).unwrap();
+        assert_eq!(&round_trip, api_error);
+    }
+
+    #[test]
+    fn redact_covers_details() {
+        let body = r#"{
+            "error": {
+                "code": 400,
+                "message": "API key secret-key not valid",
+                "details": [
+                    {
+                        "@type": "type.googleapis.com/google.rpc.BadRequest",
+                        "fieldViolations": [{"field": "key", "description": "secret-key is invalid"}]
+                    },
+                    {
+                        "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                        "reason": "API_KEY_INVALID",
+                        "metadata": {"key": "secret-key"}
+                    },
+                    {"@type": "type.googleapis.com/google.rpc.Help", "links": [{"url": "?key=secret-key"}]}
+                ]
+            }
+        }"#;
+        let err = PalmError::from_response(StatusCode::BAD_REQUEST, body).redact("secret-key");
+        let debug = format!("{:?}", err);
+        assert!(!debug.contains("secret-key"));
+        assert_eq!(debug.matches("REDACTED").count(), 4);
+    }
+}
@@ -0,0 +1,619 @@
+//! Saving a `VectorStore` to a binary file and loading it back, or searching it in place from a
+//! memory map of the file with the `mmap` feature.
+//!
+//! # Format
+//! All integers and values are little-endian; sections marked as aligned start at a multiple of
+//! 8 bytes.
+//! * Header: the magic `PALMVEC\0`, the format version (u32), flags (u32, bit 0 when vectors are
+//!   `f32`), the dimension (u32), the number of documents (u64), the index kind (u32: 0 exact,
+//!   1 IVF), its lists and probes (u32 each), the number of centroids (u32), and the model name
+//!   (u32 length and UTF-8 bytes).
+//! * Centroids, aligned: `f64` values, one centroid after the other.
+//! * Vectors, aligned: `f32` or `f64` values, one document after the other.
+//! * Lists: the IVF list of each document (u32, `u32::MAX` for none).
+//! * Metadata: the documents, as a JSON array of `{"id", "text", "metadata"}` (u64 length).
+//! * Checksum: the 64-bit FNV-1a hash of every preceding byte (u64).
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::checksum::fnv1a;
+use crate::embedding::Embedding;
+use crate::palm::PalmError;
+#[cfg(feature = "mmap")]
+use crate::vector_store::{check_embedding, search_lists, top_k, SearchResult};
+use crate::vector_store::{Document, Entry, IndexKind, VectorStore};
+
+const MAGIC: &[u8; 8] = b"PALMVEC\0";
+const VERSION: u32 = 1;
+const FLAG_F32: u32 = 1;
+const NO_LIST: u32 = u32::MAX;
+
+// Numbers the temporary files of `save`, so that concurrent saves never write the same one.
+static SAVES: AtomicU64 = AtomicU64::new(0);
+
+impl VectorStore {
+    /// Saves the store to `path`, replacing the file atomically.
+    ///
+    /// Vectors are saved as `f32` if every embedding is stored as `f32`, see `Embedding::into_f32`.
+    ///
+    /// # Example
+    /// ```
+    /// store.save("index.palmvec").expect("err");
+    /// let store = palm_api::vector_store::VectorStore::load("index.palmvec").expect("err");
+    /// ```
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PalmError> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let bytes = self.to_bytes()?;
+        let saved = std::fs::write(&temp, bytes).and_then(|()| std::fs::rename(&temp, path));
+        if saved.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        Ok(saved?)
+    }
+
+    /// Loads a store saved by `save`.
+    ///
+    /// Returns `PalmError::CorruptIndex` if the file is not a saved store or its checksum does not match.
+    pub fn load(path: impl AsRef<Path>) -> Result<VectorStore, PalmError> {
+        VectorStore::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Maps the file of a store saved by `save` into memory, and searches its vectors in place
+    /// instead of copying them to the heap, so that indexes larger than the available memory
+    /// can be searched.
+    ///
+    /// The documents, centroids and lists are decoded as by `load`.
+    ///
+    /// # Note
+    /// Opening is not free: the checksum is verified over the whole file, so every page of the
+    /// vectors is read once, at disk speed, before the store is returned. Only the memory stays
+    /// bounded, as the pages can be evicted again.
+    ///
+    /// The file must not be modified while the returned store is alive.
+    ///
+    /// # Example
+    /// ```
+    /// let store = palm_api::vector_store::VectorStore::load_mmap("index.palmvec").expect("err");
+    /// for result in store.search(&query, 5).expect("err") {
+    ///     println!("{} {}", result.document.id, result.score);
+    /// }
+    /// ```
+    #[cfg(feature = "mmap")]
+    pub fn load_mmap(path: impl AsRef<Path>) -> Result<MappedVectorStore, PalmError> {
+        let file = std::fs::File::open(path)?;
+        // Safety: the caller keeps the file unmodified while the map is alive, see the note.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let index = Index::decode(&map)?;
+        Ok(MappedVectorStore { map, index })
+    }
+
+    /// Encodes the store in the format of `save`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PalmError> {
+        let f32 = !self.entries.is_empty() && self.entries.iter().all(|e| e.embedding.is_f32());
+        let dimension = self.dimension().unwrap_or(0);
+        let model = self.model().unwrap_or_default();
+        let (kind, lists, probes) = match self.kind {
+            IndexKind::Exact => (0, 0, 0),
+            IndexKind::Ivf { lists, probes } => (1, lists, probes),
+        };
+        let element = if f32 { 4 } else { 8 };
+        let mut bytes =
+            Vec::with_capacity(64 + model.len() + self.entries.len() * dimension * element);
+        bytes.extend_from_slice(MAGIC);
+        put_u32(&mut bytes, VERSION);
+        put_u32(&mut bytes, if f32 { FLAG_F32 } else { 0 });
+        put_u32(&mut bytes, to_u32(dimension)?);
+        bytes.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        put_u32(&mut bytes, kind);
+        put_u32(&mut bytes, to_u32(lists)?);
+        put_u32(&mut bytes, to_u32(probes)?);
+        put_u32(&mut bytes, to_u32(self.centroids.len())?);
+        put_u32(&mut bytes, to_u32(model.len())?);
+        bytes.extend_from_slice(model.as_bytes());
+
+        pad(&mut bytes);
+        for centroid in &self.centroids {
+            centroid
+                .iter()
+                .for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+        }
+        pad(&mut bytes);
+        for entry in &self.entries {
+            match entry.embedding.as_f32() {
+                Some(values) if f32 => values
+                    .iter()
+                    .for_each(|value| bytes.extend_from_slice(&value.to_le_bytes())),
+                _ => entry
+                    .embedding
+                    .iter()
+                    .for_each(|value| bytes.extend_from_slice(&value.to_le_bytes())),
+            }
+        }
+        for entry in &self.entries {
+            put_u32(&mut bytes, entry.list.map_or(Ok(NO_LIST), to_u32)?);
+        }
+        let documents: Vec<&Document> = self.documents().collect();
+        let metadata = serde_json::to_vec(&documents)?;
+        bytes.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&metadata);
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Decodes a store encoded by `to_bytes`.
+    ///
+    /// Returns `PalmError::CorruptIndex` if `bytes` is not a saved store or its checksum does not match.
+    pub fn from_bytes(bytes: &[u8]) -> Result<VectorStore, PalmError> {
+        let index = Index::decode(bytes)?;
+        let vectors = &bytes[index.vectors.clone()];
+        Ok(index.into_store(vectors))
+    }
+}
+
+/// Everything but the vectors of a saved store, and where its vectors are in the file.
+#[derive(Debug, Clone)]
+struct Index {
+    kind: IndexKind,
+    model: String,
+    dimension: usize,
+    f32: bool,
+    centroids: Vec<Embedding>,
+    lists: Vec<Vec<usize>>,
+    assigned: Vec<Option<usize>>,
+    documents: Vec<Document>,
+    ids: HashMap<String, usize>,
+    vectors: Range<usize>,
+}
+
+impl Index {
+    fn decode(bytes: &[u8]) -> Result<Index, PalmError> {
+        let Some(content_len) = bytes.len().checked_sub(8) else {
+            return Err(corrupt("the file is truncated"));
+        };
+        let (content, checksum) = bytes.split_at(content_len);
+        if !content.starts_with(MAGIC) {
+            return Err(corrupt("the file is not a saved vector store"));
+        }
+        if fnv1a(content).to_le_bytes() != checksum {
+            return Err(corrupt("the checksum does not match"));
+        }
+        let mut reader = Reader {
+            bytes: content,
+            offset: MAGIC.len(),
+        };
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(corrupt(&format!("unsupported format version {}", version)));
+        }
+        let f32 = reader.u32()? & FLAG_F32 != 0;
+        let dimension = reader.u32()? as usize;
+        let count = usize::try_from(reader.u64()?).map_err(|_| corrupt("too many documents"))?;
+        let kind = match (reader.u32()?, reader.u32()?, reader.u32()?) {
+            (0, _, _) => IndexKind::Exact,
+            (1, lists, probes) => IndexKind::Ivf {
+                lists: lists as usize,
+                probes: probes as usize,
+            },
+            (kind, _, _) => return Err(corrupt(&format!("unknown index kind {}", kind))),
+        };
+        let centroid_count = reader.u32()? as usize;
+        let model_len = reader.u32()? as usize;
+        let model = std::str::from_utf8(reader.take(model_len)?)
+            .map_err(|_| corrupt("the model name is not UTF-8"))?
+            .to_string();
+
+        reader.align();
+        let centroids = reader.values(centroid_count, dimension, 8)?;
+        let centroids = (0..centroid_count)
+            .map(|index| {
+                let values = &centroids[index * dimension * 8..(index + 1) * dimension * 8];
+                Embedding::new(model.as_str(), values.chunks_exact(8).map(f64_le).collect())
+            })
+            .collect();
+        reader.align();
+        let element = if f32 { 4 } else { 8 };
+        let vectors_start = reader.offset;
+        reader.values(count, dimension, element)?;
+        let vectors = vectors_start..reader.offset;
+        let assigned = reader.values(count, 1, 4)?;
+        let metadata_len =
+            usize::try_from(reader.u64()?).map_err(|_| corrupt("the metadata is too large"))?;
+        let documents: Vec<Document> = serde_json::from_slice(reader.take(metadata_len)?)
+            .map_err(|err| corrupt(&format!("invalid metadata: {}", err)))?;
+        if reader.offset != content.len() {
+            return Err(corrupt("unexpected bytes after the metadata"));
+        }
+        if documents.len() != count {
+            return Err(corrupt(
+                "the metadata does not match the number of documents",
+            ));
+        }
+
+        let mut lists = vec![Vec::new(); centroid_count];
+        let mut ids = HashMap::with_capacity(count);
+        let assigned = assigned
+            .chunks_exact(4)
+            .enumerate()
+            .map(|(index, list)| {
+                let list = match u32::from_le_bytes(list.try_into().unwrap_or_default()) {
+                    NO_LIST => None,
+                    list if (list as usize) < centroid_count => Some(list as usize),
+                    list => return Err(corrupt(&format!("unknown list {}", list))),
+                };
+                if let Some(list) = list {
+                    lists[list].push(index);
+                }
+                Ok(list)
+            })
+            .collect::<Result<Vec<_>, PalmError>>()?;
+        for (index, document) in documents.iter().enumerate() {
+            if ids.insert(document.id.clone(), index).is_some() {
+                return Err(corrupt(&format!("duplicate document id {}", document.id)));
+            }
+        }
+        Ok(Index {
+            kind,
+            model,
+            dimension,
+            f32,
+            centroids,
+            lists,
+            assigned,
+            documents,
+            ids,
+            vectors,
+        })
+    }
+
+    // The bytes of the vector of document `index`, given the vector section.
+    fn vector<'a>(&self, vectors: &'a [u8], index: usize) -> &'a [u8] {
+        let len = self.dimension * if self.f32 { 4 } else { 8 };
+        &vectors[index * len..(index + 1) * len]
+    }
+
+    fn embedding(&self, vectors: &[u8], index: usize) -> Embedding {
+        let vector = self.vector(vectors, index);
+        if self.f32 {
+            Embedding::from_f32(
+                self.model.as_str(),
+                vector.chunks_exact(4).map(f32_le).collect(),
+            )
+        } else {
+            Embedding::new(
+                self.model.as_str(),
+                vector.chunks_exact(8).map(f64_le).collect(),
+            )
+        }
+    }
+
+    fn into_store(self, vectors: &[u8]) -> VectorStore {
+        let entries = self
+            .documents
+            .iter()
+            .zip(&self.assigned)
+            .enumerate()
+            .map(|(index, (document, &list))| Entry {
+                document: document.clone(),
+                embedding: self.embedding(vectors, index),
+                list,
+            })
+            .collect();
+        let mut store = VectorStore::with_index(self.kind);
+        store.entries = entries;
+        store.ids = self.ids;
+        store.centroids = self.centroids;
+        store.lists = self.lists;
+        store
+    }
+}
+
+/// A store loaded by `VectorStore::load_mmap`, whose vectors stay in the mapped file.
+///
+/// The store is read-only; `to_store()` copies it into a `VectorStore` to modify it.
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MappedVectorStore {
+    map: memmap2::Mmap,
+    index: Index,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedVectorStore {
+    /// Returns the kind of index used by `search`.
+    pub fn index_kind(&self) -> IndexKind {
+        self.index.kind
+    }
+
+    /// Returns the number of documents.
+    pub fn len(&self) -> usize {
+        self.index.documents.len()
+    }
+
+    /// Returns whether the store has no document.
+    pub fn is_empty(&self) -> bool {
+        self.index.documents.is_empty()
+    }
+
+    /// Returns the model of the embeddings, if the store has any document.
+    pub fn model(&self) -> Option<&str> {
+        (!self.is_empty()).then_some(self.index.model.as_str())
+    }
+
+    /// Returns the dimension of the embeddings, if the store has any document.
+    pub fn dimension(&self) -> Option<usize> {
+        (!self.is_empty()).then_some(self.index.dimension)
+    }
+
+    /// Returns the document `id`.
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.index
+            .ids
+            .get(id)
+            .map(|&index| &self.index.documents[index])
+    }
+
+    /// Returns the documents, in no particular order.
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.index.documents.iter()
+    }
+
+    /// Returns the embedding of the document `id`, read from the file.
+    pub fn embedding(&self, id: &str) -> Option<Embedding> {
+        let index = *self.index.ids.get(id)?;
+        Some(self.index.embedding(self.vectors(), index))
+    }
+
+    /// Returns the `k` documents most similar to `query`, most similar first, see
+    /// `VectorStore::search`.
+    pub fn search(&self, query: &Embedding, k: usize) -> Result<Vec<SearchResult<'_>>, PalmError> {
+        let probes = match self.index.kind {
+            IndexKind::Ivf { probes, .. } if !self.index.centroids.is_empty() => probes,
+            _ => return self.search_exact(query, k),
+        };
+        self.check_embedding(query)?;
+        let query = query.clone().normalize();
+        let index = &self.index;
+        let scored = search_lists(&index.centroids, &index.lists, probes, &query, k, |doc| {
+            self.score(&query, doc)
+        });
+        Ok(self.results(scored))
+    }
+
+    /// Returns the `k` documents most similar to `query`, comparing it with every document.
+    pub fn search_exact(
+        &self,
+        query: &Embedding,
+        k: usize,
+    ) -> Result<Vec<SearchResult<'_>>, PalmError> {
+        self.check_embedding(query)?;
+        let query = query.clone().normalize();
+        let candidates = (0..self.len()).map(|index| (index, self.score(&query, index)));
+        Ok(self.results(top_k(candidates, k)))
+    }
+
+    /// Copies the store into a `VectorStore`, e.g. to insert documents.
+    pub fn to_store(&self) -> VectorStore {
+        self.index.clone().into_store(self.vectors())
+    }
+
+    fn vectors(&self) -> &[u8] {
+        &self.map[self.index.vectors.clone()]
+    }
+
+    // The dot product of the normalized `query` with the stored, normalized, vector.
+    fn score(&self, query: &Embedding, index: usize) -> f64 {
+        let vector = self.index.vector(self.vectors(), index);
+        if self.index.f32 {
+            let values = vector.chunks_exact(4).map(|bytes| f64::from(f32_le(bytes)));
+            query.iter().zip(values).map(|(a, b)| a * b).sum()
+        } else {
+            let values = vector.chunks_exact(8).map(f64_le);
+            query.iter().zip(values).map(|(a, b)| a * b).sum()
+        }
+    }
+
+    fn results(&self, scored: Vec<(usize, f64)>) -> Vec<SearchResult<'_>> {
+        scored
+            .into_iter()
+            .map(|(index, score)| SearchResult {
+                document: &self.index.documents[index],
+                score,
+            })
+            .collect()
+    }
+
+    fn check_embedding(&self, embedding: &Embedding) -> Result<(), PalmError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        check_embedding(&self.index.model, self.index.dimension, embedding)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PalmError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt("the file is truncated"))?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    // Takes `count` groups of `dimension` values of `element` bytes.
+    fn values(
+        &mut self,
+        count: usize,
+        dimension: usize,
+        element: usize,
+    ) -> Result<&'a [u8], PalmError> {
+        let len = count
+            .checked_mul(dimension)
+            .and_then(|len| len.checked_mul(element))
+            .ok_or_else(|| corrupt("the file is truncated"))?;
+        self.take(len)
+    }
+
+    fn u32(&mut self) -> Result<u32, PalmError> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().unwrap_or_default(),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64, PalmError> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().unwrap_or_default(),
+        ))
+    }
+
+    fn align(&mut self) {
+        self.offset = self.offset.next_multiple_of(8).min(self.bytes.len());
+    }
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn pad(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(8), 0);
+}
+
+fn to_u32(value: usize) -> Result<u32, PalmError> {
+    u32::try_from(value)
+        .map_err(|_| PalmError::InvalidArgument(format!("{} does not fit in an index file", value)))
+}
+
+fn f64_le(bytes: &[u8]) -> f64 {
+    f64::from_le_bytes(bytes.try_into().unwrap_or_default())
+}
+
+fn f32_le(bytes: &[u8]) -> f32 {
+    f32::from_le_bytes(bytes.try_into().unwrap_or_default())
+}
+
+fn corrupt(message: &str) -> PalmError {
+    PalmError::CorruptIndex(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embedding::Embedding;
+    use crate::palm::PalmError;
+    use crate::vector_store::{Document, IndexKind, VectorStore};
+
+    fn store() -> VectorStore {
+        let mut store = VectorStore::with_index(IndexKind::Ivf {
+            lists: 2,
+            probes: 1,
+        });
+        for (id, values) in [("a", [1.0, 0.1]), ("b", [0.9, 0.0]), ("c", [-1.0, 0.2])] {
+            store
+                .insert(
+                    Document::new(id, format!("text {}", id)).with_metadata("source", "tests"),
+                    Embedding::new("embedding-gecko-001", values.to_vec()).into_f32(),
+                )
+                .expect("err");
+        }
+        store.build_index();
+        store
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let store = store();
+        let bytes = store.to_bytes().expect("err");
+        let loaded = VectorStore::from_bytes(&bytes).expect("err");
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.model(), Some("embedding-gecko-001"));
+        assert_eq!(loaded.index_kind(), store.index_kind());
+        assert_eq!(loaded.get("c"), store.get("c"));
+        let query = Embedding::new("embedding-gecko-001", vec![-1.0, 0.0]);
+        let expected = store.search(&query, 2).expect("err");
+        assert_eq!(loaded.search(&query, 2).expect("err"), expected);
+        assert_eq!(
+            VectorStore::from_bytes(&VectorStore::new().to_bytes().expect("err"))
+                .expect("err")
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut bytes = store().to_bytes().expect("err");
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        assert!(matches!(
+            VectorStore::from_bytes(&bytes),
+            Err(PalmError::CorruptIndex(_))
+        ));
+        assert!(matches!(
+            VectorStore::from_bytes(&bytes[..4]),
+            Err(PalmError::CorruptIndex(_))
+        ));
+    }
+
+    #[test]
+    fn saves_and_loads_files() {
+        let path = std::env::temp_dir().join(format!("palm_api-{}.palmvec", std::process::id()));
+        store().save(&path).expect("err");
+        let loaded = VectorStore::load(&path).expect("err");
+        assert_eq!(loaded.len(), 3);
+        #[cfg(feature = "mmap")]
+        {
+            let mapped = VectorStore::load_mmap(&path).expect("err");
+            assert_eq!(mapped.len(), 3);
+            assert_eq!(mapped.get("a"), loaded.get("a"));
+            let query = Embedding::new("embedding-gecko-001", vec![1.0, 0.0]);
+            assert_eq!(
+                mapped.search(&query, 2).expect("err"),
+                loaded.search(&query, 2).expect("err")
+            );
+            assert_eq!(
+                mapped.search_exact(&query, 3).expect("err"),
+                loaded.search_exact(&query, 3).expect("err")
+            );
+            assert!(mapped.embedding("b").expect("err").is_f32());
+            assert_eq!(mapped.to_store().get("c"), loaded.get("c"));
+        }
+        std::fs::remove_file(&path).expect("err");
+        assert!(matches!(VectorStore::load(&path), Err(PalmError::Io(_))));
+    }
+
+    #[test]
+    fn concurrent_saves_use_their_own_temporary_files() {
+        let directory = std::env::temp_dir().join(format!("palm_api-saves-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("err");
+        let path = directory.join("index.palmvec");
+        let store = store();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| store.save(&path).expect("err"));
+            }
+        });
+        assert_eq!(VectorStore::load(&path).expect("err").len(), 3);
+        // Renaming over a directory fails once the temporary file is written.
+        std::fs::create_dir(directory.join("taken")).expect("err");
+        assert!(store.save(directory.join("taken")).is_err());
+        assert_eq!(std::fs::read_dir(&directory).expect("err").count(), 2);
+        std::fs::remove_dir_all(&directory).expect("err");
+    }
+}
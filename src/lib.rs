@@ -33,12 +33,63 @@
 //! println!("{}", response2.candidates.unwrap()[0].content);
 //! ```
 //!
+//! ## Async client
+//! Enable the `async` feature and use `AsyncPalmClient` from within an async runtime.
+//! The blocking `PalmClient` is behind the `blocking` feature, enabled by default.
+//! ```rust
+//! use palm_api::palm::{create_async_client, new_text_body};
+//!
+//! let client = create_async_client(API_KEY.to_string());
+//! let mut text_body = new_text_body();
+//! text_body.set_text_prompt("The opposite of hot is".to_string());
+//! let response = client
+//!     .generate_text("text-bison-001".to_string(), text_body)
+//!     .await
+//!     .expect("An error has occured.");
+//! println!("{}", response.candidates.unwrap()[0].output);
+//! ```
+//!
 
+#[cfg(feature = "async")]
+mod async_client;
+pub mod auth;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod batch;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod builder;
+mod checksum;
+pub mod citation;
+pub mod embedding;
+pub mod embedding_cache;
+pub mod error;
+#[cfg(feature = "vector-store")]
+mod index_file;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod model_cache;
 pub mod palm;
-
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod rate_limit;
+pub mod request;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod retry;
+pub mod safety;
+#[cfg(feature = "service-account")]
+mod service_account;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod session;
 #[cfg(test)]
+mod test_server;
+#[cfg(feature = "vector-store")]
+pub mod vector_store;
+
+pub use crate::error::{ApiError, PalmError};
+
+#[cfg(all(test, feature = "blocking"))]
+#[allow(clippy::len_zero)]
 mod tests {
-    use crate::palm::{create_client, new_chat_body, new_text_body};
+    use crate::palm::{
+        create_client, new_chat_body, new_text_body, HarmBlockThreshold, HarmCategory,
+    };
 
     #[test]
     fn list_models_works() {
@@ -80,7 +131,7 @@ mod tests {
                 "say something cool and nice!".to_string(),
             )
             .expect("err");
-        assert!(embeddings.len() > 0);
+        assert!(embeddings.dimension() > 0);
     }
 
     #[test]
@@ -109,10 +160,8 @@ mod tests {
     fn generate_text_works() {
         let my_client = create_client("".to_string());
         let mut text_body = new_text_body();
-        text_body.append_safety_setting(
-            "HARM_CATEGORY_TOXICITY".to_string(),
-            "BLOCK_LOW_AND_ABOVE".to_string(),
-        );
+        text_body
+            .append_safety_setting(HarmCategory::Toxicity, HarmBlockThreshold::BlockLowAndAbove);
         text_body.set_candidate_count(2);
         text_body.set_temperature(1.0);
         text_body.set_text_prompt("Write a story about a magic backpack.".to_string());
@@ -0,0 +1,125 @@
+//! Caching model metadata, so that it is fetched once per model instead of once per request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::palm::Model;
+
+/// Models fetched with `get_model()`, kept for `ttl` and shared by every clone of a client.
+#[derive(Debug)]
+pub(crate) struct ModelCache {
+    ttl: Duration,
+    models: Mutex<HashMap<String, (Instant, Model)>>,
+}
+
+impl ModelCache {
+    /// Creates a cache keeping models for `ttl`; a zero `ttl` disables caching.
+    pub(crate) fn new(ttl: Duration) -> ModelCache {
+        ModelCache {
+            ttl,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached `model`, unless it expired.
+    pub(crate) fn get(&self, model: &str) -> Option<Model> {
+        let models = self.models.lock().unwrap_or_else(|err| err.into_inner());
+        match models.get(key(model)) {
+            Some((fetched_at, model)) if fetched_at.elapsed() < self.ttl => Some(model.clone()),
+            _ => None,
+        }
+    }
+
+    /// Caches `info` as the metadata of `model`.
+    pub(crate) fn insert(&self, model: &str, info: &Model) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut models = self.models.lock().unwrap_or_else(|err| err.into_inner());
+        models.insert(key(model).to_string(), (Instant::now(), info.clone()));
+    }
+
+    /// Removes `model` from the cache, or every model if `model` is `None`.
+    pub(crate) fn invalidate(&self, model: Option<&str>) {
+        let mut models = self.models.lock().unwrap_or_else(|err| err.into_inner());
+        match model {
+            Some(model) => {
+                models.remove(key(model));
+            }
+            None => models.clear(),
+        }
+    }
+}
+
+// `get_model()` accepts both `text-bison-001` and `models/text-bison-001`.
+fn key(model: &str) -> &str {
+    model.strip_prefix("models/").unwrap_or(model)
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use crate::palm::{new_text_body, PalmClient};
+    use crate::test_server::{Reply, TestServer};
+    use std::time::Duration;
+
+    const MODEL: &str = r#"{"name": "models/text-bison-001", "version": "001",
+        "displayName": "Text Bison", "description": "Model", "inputTokenLimit": 8196,
+        "outputTokenLimit": 1024, "supportedGenerationMethods": ["generateText"]}"#;
+
+    #[test]
+    fn caches_models_until_invalidated() {
+        let server = TestServer::start(vec![
+            Reply::json(200, MODEL),
+            Reply::json(200, MODEL),
+            Reply::json(200, MODEL),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        client.get_model("text-bison-001".to_string()).expect("err");
+        client
+            .clone()
+            .get_model("models/text-bison-001".to_string())
+            .expect("err");
+        assert_eq!(server.requests().len(), 1);
+        client.invalidate_model("text-bison-001");
+        client.get_model("text-bison-001".to_string()).expect("err");
+        assert_eq!(server.requests().len(), 2);
+
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .model_cache_ttl(Duration::ZERO)
+            .build()
+            .expect("err");
+        client.get_model("text-bison-001".to_string()).expect("err");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn generate_text_omits_unset_parameters() {
+        let server = TestServer::start(vec![Reply::json(200, r#"{"candidates": []}"#)]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let mut text_body = new_text_body();
+        text_body.set_text_prompt("The opposite of hot is".to_string());
+        text_body.set_temperature(0.5);
+        client
+            .generate_text("text-bison-001".to_string(), text_body)
+            .expect("err");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].path,
+            "/v1beta2/models/text-bison-001:generateText"
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).expect("err");
+        assert_eq!(body["temperature"], 0.5);
+        assert!(body.get("topP").is_none());
+        assert!(body.get("topK").is_none());
+    }
+}
@@ -1,12 +1,49 @@
+#[cfg(any(feature = "blocking", feature = "async"))]
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::io::Read;
+#[cfg(feature = "blocking")]
+use std::sync::Arc;
 
-const ENDPOINT: &str = "https://generativelanguage.googleapis.com";
+#[cfg(feature = "async")]
+pub use crate::async_client::{create_async_client, AsyncPalmClient};
+#[cfg(feature = "blocking")]
+use crate::auth::Auth;
+#[cfg(all(feature = "blocking", feature = "service-account"))]
+use crate::auth::ServiceAccount;
+#[cfg(feature = "blocking")]
+use crate::batch::{assemble, chunk_results, EmbedResult, WorkQueue};
+#[cfg(feature = "blocking")]
+use crate::builder::ClientConfig;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub use crate::builder::{Async, Blocking, PalmClientBuilder};
+pub use crate::embedding::Embedding;
+#[cfg(feature = "blocking")]
+use crate::embedding_cache::EmbeddingCache;
+pub use crate::error::{ApiError, PalmError};
+#[cfg(feature = "blocking")]
+use crate::rate_limit::{estimate_tokens, Cost, Method};
+pub use crate::request::{ChatRequest, ChatRequestBuilder, TextRequest, TextRequestBuilder};
+#[cfg(feature = "blocking")]
+use crate::retry::{retry_after, Failure};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use crate::safety::SafetyPolicy;
+#[cfg(all(feature = "blocking", feature = "service-account"))]
+use crate::service_account::parse_token_response;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) const ENDPOINT: &str = "https://generativelanguage.googleapis.com";
+const MAX_CANDIDATE_COUNT: u32 = 8;
+const MAX_STOP_SEQUENCES: usize = 5;
 
 /// A client configured with a PaLM API key and an API endpoint.
+///
+/// Requests block the calling thread; use `AsyncPalmClient` from async code.
+/// Clones share the same connection pool, so a client can be cloned into every worker thread.
+#[cfg(feature = "blocking")]
+#[derive(Clone)]
 pub struct PalmClient {
-    api_key: String,
-    endpoint: String,
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) client: reqwest::blocking::Client,
 }
 
 /// Creates a PalmClient.
@@ -20,15 +57,19 @@ pub struct PalmClient {
 /// const API_KEY: &str = "api key here";
 /// let client = palm_api::palm::create_client(API_KEY.to_string());
 /// ```
+///
+/// # Panics
+/// Panics if the HTTP client cannot be initialized; use `PalmClient::builder()` to handle that error.
+#[cfg(feature = "blocking")]
 pub fn create_client(api_key: String) -> PalmClient {
-    PalmClient {
-        api_key: api_key,
-        endpoint: ENDPOINT.to_string(),
-    }
+    PalmClient::builder()
+        .api_key(api_key)
+        .build()
+        .expect("failed to initialize the HTTP client")
 }
 
 /// Information about any model.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Model {
     /// Required. The resource name of the Model.
@@ -69,92 +110,189 @@ pub struct Model {
     pub top_k: Option<i32>,
 }
 
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
-struct ListRes {
-    models: Vec<Model>,
+pub(crate) struct ListRes {
+    pub(crate) models: Vec<Model>,
 }
 
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct TokenRes {
-    token_count: u32,
+pub(crate) struct TokenRes {
+    pub(crate) token_count: u32,
 }
 
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
-struct TokenBody {
-    prompt: Messages,
+pub(crate) struct TokenBody {
+    prompt: MessagePrompt,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Messages {
-    messages: Vec<Message>,
+/// The base unit of structured text, a message of a conversation.
+///
+/// # Example
+/// ```
+/// use palm_api::palm::Message;
+///
+/// let history = vec![
+///     Message::with_author("user", "Hello."),
+///     Message::with_author("assistant", "Hi! How can I help you?"),
+///     Message::with_author("user", "What can you do?"),
+/// ];
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    /// Optional. The author of this Message.
+    /// This serves as a key for tagging the content of this Message when it is fed to the model as text.
+    /// The author can be any alphanumeric string; it is left to the model when empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    /// Required. The text content of the structured Message.
+    pub content: String,
+    /// Output only. Citation information for model-generated content in this Message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub citation_metadata: Option<CitationMetadata>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    content: String,
+impl Message {
+    /// Creates a message without an author.
+    pub fn new(content: impl Into<String>) -> Message {
+        Message {
+            content: content.into(),
+            ..Message::default()
+        }
+    }
+
+    /// Creates a message written by `author`.
+    pub fn with_author(author: impl Into<String>, content: impl Into<String>) -> Message {
+        Message {
+            author: author.into(),
+            content: content.into(),
+            citation_metadata: None,
+        }
+    }
+}
+
+/// A collection of source attributions for a piece of content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CitationMetadata {
+    /// Citations to sources for a specific response.
+    #[serde(default)]
+    pub citation_sources: Vec<CitationSource>,
 }
 
+/// A citation to a source for a portion of a specific response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CitationSource {
+    /// Optional. Start of segment of the response that is attributed to this source, in bytes.
+    pub start_index: Option<u32>,
+    /// Optional. End of the attributed segment, exclusive, in bytes.
+    pub end_index: Option<u32>,
+    /// Optional. URI that is attributed as a source for a portion of the text.
+    pub uri: Option<String>,
+    /// Optional. License for the GitHub project that is attributed as a source for segment.
+    pub license: Option<String>,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
-struct EmbedBody {
-    text: String,
+pub(crate) struct EmbedBody {
+    pub(crate) text: String,
 }
 
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
-struct EmbedRes {
-    embedding: EmbedValue,
+pub(crate) struct EmbedRes {
+    pub(crate) embedding: EmbedValue,
 }
 
+#[cfg(any(feature = "blocking", feature = "async"))]
+#[derive(Serialize, Debug)]
+pub(crate) struct BatchEmbedBody<'a> {
+    pub(crate) texts: &'a [String],
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+#[derive(Deserialize, Debug)]
+pub(crate) struct BatchEmbedRes {
+    #[serde(default)]
+    pub(crate) embeddings: Vec<EmbedValue>,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
 #[derive(Serialize, Deserialize, Debug)]
-struct EmbedValue {
-    value: Vec<f64>,
+pub(crate) struct EmbedValue {
+    pub(crate) value: Vec<f64>,
 }
 
 /// JSON Payload for POST request required to generate message (chat).
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// `generateMessage` takes no safety settings; `ChatRes::filters` reports what was blocked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatBody {
     prompt: MessagePrompt,
-    temperature: f64,
-    candidate_count: u32,
-    top_p: f64,
-    top_k: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Example {
     input: Message,
     output: Message,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct MessagePrompt {
-    context: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     examples: Vec<Example>,
     messages: Vec<Message>,
 }
 
+/// The reason content was blocked during request processing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BlockedReason {
+    /// A blocked reason was not specified.
+    #[serde(rename = "BLOCKED_REASON_UNSPECIFIED")]
+    Unspecified,
+    /// Content was blocked by safety settings.
+    Safety,
+    /// Content was blocked, but the reason is uncategorized.
+    Other,
+    /// A reason returned by the API that this version of the crate does not know.
+    #[serde(other)]
+    Unknown,
+}
+
 /// Content filtering metadata associated with processing a single request.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ContentFilter {
     /// The reason content was blocked during request processing.
-    pub reason: String,
+    pub reason: BlockedReason,
+    /// A string that describes the filtering behavior in more detail.
+    pub message: Option<String>,
 }
 
 /// Message response to generate message (chat).
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MessageRes {
-    /// Optional. The author of this Message.
-    /// This serves as a key for tagging the content of this Message when it is fed to the model as text.
-    /// The author can be any alphanumeric string.
-    pub author: String,
-    /// Required. The text content of the structured Message.
-    pub content: String,
-}
+/// Responses are `Message`s, so they can be passed back in the history of a `ChatBody`.
+pub type MessageRes = Message;
 
 /// The response from the model.
 /// This includes candidate messages and conversation history in the form of chronologically-ordered messages.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatRes {
     /// The conversation history used by the model.
     pub messages: Vec<MessageRes>,
@@ -165,7 +303,7 @@ pub struct ChatRes {
     ///
     /// # Example
     /// ```
-    /// println!("{}",chat_res.filters.unwrap()[0].reason);
+    /// println!("{:?}",chat_res.filters.unwrap()[0].reason);
     /// ```
     pub filters: Option<Vec<ContentFilter>>,
     /// Candidate response messages from the model.
@@ -175,37 +313,143 @@ pub struct ChatRes {
     /// println!("{}",chat_res.candidates.unwrap()[0].content);
     /// ```
     pub candidates: Option<Vec<MessageRes>>,
-    chat_body: Option<ChatBody>,
-    model: Option<String>,
+    pub(crate) chat_body: Option<ChatBody>,
+    pub(crate) model: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct TextPrompt {
     text: String,
 }
 
+/// The category of a rating.
+/// These categories cover various kinds of harms that developers may wish to adjust.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HarmCategory {
+    /// Category is unspecified.
+    #[serde(rename = "HARM_CATEGORY_UNSPECIFIED")]
+    Unspecified,
+    /// Negative or harmful comments targeting identity and/or protected attribute.
+    #[serde(rename = "HARM_CATEGORY_DEROGATORY")]
+    Derogatory,
+    /// Content that is rude, disrespectful, or profane.
+    #[serde(rename = "HARM_CATEGORY_TOXICITY")]
+    Toxicity,
+    /// Describes scenarios depicting violence against an individual or group, or general descriptions of gore.
+    #[serde(rename = "HARM_CATEGORY_VIOLENCE")]
+    Violence,
+    /// Contains references to sexual acts or other lewd content.
+    #[serde(rename = "HARM_CATEGORY_SEXUAL")]
+    Sexual,
+    /// Promotes unchecked medical advice.
+    #[serde(rename = "HARM_CATEGORY_MEDICAL")]
+    Medical,
+    /// Dangerous content that promotes, facilitates, or encourages harmful acts.
+    #[serde(rename = "HARM_CATEGORY_DANGEROUS")]
+    Dangerous,
+    /// A category returned by the API that this version of the crate does not know.
+    /// It should not be sent in a request.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Block at and beyond a specified harm probability.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HarmBlockThreshold {
+    /// Threshold is unspecified.
+    #[serde(rename = "HARM_BLOCK_THRESHOLD_UNSPECIFIED")]
+    Unspecified,
+    /// Content with NEGLIGIBLE will be allowed.
+    BlockLowAndAbove,
+    /// Content with NEGLIGIBLE and LOW will be allowed.
+    BlockMediumAndAbove,
+    /// Content with NEGLIGIBLE, LOW, and MEDIUM will be allowed.
+    BlockOnlyHigh,
+    /// All content will be allowed.
+    BlockNone,
+    /// A threshold returned by the API that this version of the crate does not know.
+    /// It should not be sent in a request.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The probability that a piece of content is harmful.
+/// Probabilities are ordered from `Unspecified` and `Unknown` to `High`, so they can be compared.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HarmProbability {
+    /// Probability is unspecified.
+    #[serde(rename = "HARM_PROBABILITY_UNSPECIFIED")]
+    Unspecified,
+    /// Content has a negligible chance of being unsafe.
+    Negligible,
+    /// Content has a low chance of being unsafe.
+    Low,
+    /// Content has a medium chance of being unsafe.
+    Medium,
+    /// Content has a high chance of being unsafe.
+    High,
+    /// A probability returned by the API that this version of the crate does not know.
+    /// It is ordered below `Negligible`.
+    #[serde(other)]
+    Unknown,
+}
+
+impl HarmProbability {
+    fn rank(self) -> u8 {
+        match self {
+            HarmProbability::Unspecified => 0,
+            HarmProbability::Unknown => 1,
+            HarmProbability::Negligible => 2,
+            HarmProbability::Low => 3,
+            HarmProbability::Medium => 4,
+            HarmProbability::High => 5,
+        }
+    }
+}
+
+impl PartialOrd for HarmProbability {
+    fn partial_cmp(&self, other: &HarmProbability) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HarmProbability {
+    fn cmp(&self, other: &HarmProbability) -> std::cmp::Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
 /// Safety setting, affecting the safety-blocking behavior.
 /// Passing a safety setting for a category changes the allowed proability that content is blocked.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SafetySetting {
     /// Required. The category for this setting.
-    pub category: String,
+    pub category: HarmCategory,
     /// Required. Controls the probability threshold at which harm is blocked.
-    pub threshold: String,
+    pub threshold: HarmBlockThreshold,
 }
 
 /// The request body for generate_text() function.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct TextBody {
     prompt: TextPrompt,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     safety_settings: Vec<SafetySetting>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     stop_sequences: Vec<String>,
-    temperature: f64,
-    candidate_count: u32,
-    max_output_tokens: u32,
-    top_p: f64,
-    top_k: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
 }
 
 /// Safety rating for a piece of content.
@@ -214,9 +458,9 @@ pub struct TextBody {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SafetyRating {
     /// Required. The category for this rating.
-    pub category: String,
+    pub category: HarmCategory,
     /// Required. The probability of harm for this content.
-    pub probability: String,
+    pub probability: HarmProbability,
 }
 
 /// Output text returned from a model.
@@ -228,6 +472,8 @@ pub struct TextCompletion {
     /// Ratings for the safety of a response.
     /// There is at most one rating per category.
     pub safety_ratings: Vec<SafetyRating>,
+    /// Citation information for model-generated `output`.
+    pub citation_metadata: Option<CitationMetadata>,
 }
 
 /// Safety feedback for an entire request.
@@ -266,7 +512,7 @@ pub struct TextRes {
     ///
     /// # Example
     /// ```
-    /// println!("{}",text_res.filters.unwrap()[0].reason);
+    /// println!("{:?}",text_res.filters.unwrap()[0].reason);
     /// ```
     pub filters: Option<Vec<ContentFilter>>,
     /// Returns any safety feedback related to content filtering.
@@ -278,23 +524,200 @@ pub struct TextRes {
     pub safety_feedback: Option<Vec<SafetyFeedback>>,
 }
 
+/// Decodes the body of a successful response, or turns the status into a `PalmError`.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn parse_response<T: DeserializeOwned>(
+    status: reqwest::StatusCode,
+    body: &str,
+) -> Result<T, PalmError> {
+    if !status.is_success() {
+        return Err(PalmError::from_response(status, body));
+    }
+    Ok(serde_json::from_str(body)?)
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn new_token_body(message_list: Vec<String>) -> TokenBody {
+    let mut prompt = new_message_prompt();
+    prompt.messages = message_list.into_iter().map(Message::new).collect();
+    TokenBody { prompt }
+}
+
+/// Extends the conversation of `previous_response` with the chosen candidate and the reply.
+/// Returns the model to send the new `ChatBody` to.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn new_reply_body(
+    previous_response: &ChatRes,
+    reply_message: String,
+    candidate_index: usize,
+) -> Result<(String, ChatBody), PalmError> {
+    let (Some(chat_body), Some(model)) = (&previous_response.chat_body, &previous_response.model)
+    else {
+        return Err(PalmError::InvalidArgument(
+            "previous_response was not returned by chat()".to_string(),
+        ));
+    };
+    let candidates = previous_response.candidates.as_deref().unwrap_or_default();
+    let candidate = candidates
+        .get(candidate_index)
+        .ok_or(PalmError::MissingCandidate {
+            index: candidate_index,
+            available: candidates.len(),
+        })?;
+    // The server returns the history it used, authors included; the reply keeps the author
+    // of the last message, so the model sees the same participants.
+    let mut messages = match previous_response.messages.is_empty() {
+        true => chat_body.messages().to_vec(),
+        false => previous_response.messages.clone(),
+    };
+    let author = messages
+        .last()
+        .map(|message| message.author.clone())
+        .unwrap_or_default();
+    messages.push(Message::with_author(
+        candidate.author.clone(),
+        candidate.content.clone(),
+    ));
+    messages.push(Message::with_author(author, reply_message));
+    let mut chat_body = chat_body.clone();
+    chat_body.set_messages(messages);
+    Ok((model.clone(), chat_body))
+}
+
+#[cfg(feature = "blocking")]
 impl PalmClient {
-    // functions for list_models
-    fn fetch_models(
+    /// Returns a `PalmClientBuilder` to configure the endpoint, timeouts, proxy and headers of a client.
+    pub fn builder() -> PalmClientBuilder {
+        PalmClientBuilder::new()
+    }
+
+    fn fetch<T: DeserializeOwned>(&self, url: String) -> Result<T, PalmError> {
+        self.send(self.client.get(url), None)
+    }
+
+    // Posts `json`, taking `cost` from the rate limiter before every attempt.
+    fn post<B: Serialize, T: DeserializeOwned>(
         &self,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let mut res = reqwest::blocking::get(format!(
-            "{}/v1beta2/models?key={}",
-            self.endpoint, self.api_key
-        ))?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
+        url: String,
+        json: &B,
+        cost: Cost,
+    ) -> Result<T, PalmError> {
+        self.send(self.client.post(url).json(json), Some(cost))
     }
 
-    fn parse_models(&self, body: String) -> serde_json::Result<ListRes> {
-        let parsed_models = serde_json::from_str(&body.as_str())?;
-        Ok(parsed_models)
+    fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        cost: Option<Cost>,
+    ) -> Result<T, PalmError> {
+        let mut attempt = 1;
+        #[cfg(feature = "service-account")]
+        let mut reauthorized = false;
+        loop {
+            if let Some(cost) = cost {
+                self.wait_for_quota(cost);
+            }
+            let current = request.try_clone().ok_or_else(|| {
+                PalmError::InvalidArgument("request body cannot be retried".to_string())
+            })?;
+            let mut access_token = None;
+            let failure = match self.send_once(current, &mut access_token) {
+                Ok(value) => return Ok(value),
+                Err(failure) => failure,
+            };
+            let error = self.config.redact(failure.error, access_token.as_deref());
+            // The token may have been revoked before it expired, so a new one is fetched once.
+            #[cfg(feature = "service-account")]
+            if let (Auth::ServiceAccount(account), PalmError::Unauthorized(_), Some(token)) =
+                (&self.config.auth, &error, &access_token)
+            {
+                if !reauthorized {
+                    reauthorized = true;
+                    account.discard_token(token);
+                    continue;
+                }
+            }
+            let policy = &self.config.retry_policy;
+            match policy.next_delay(attempt, &error, failure.status, failure.retry_after) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+
+    // Sends `request` once, keeping the access token it carries in `access_token`.
+    fn send_once<T: DeserializeOwned>(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        access_token: &mut Option<String>,
+    ) -> Result<T, Failure> {
+        *access_token = self.access_token()?;
+        let headers = self.config.auth_headers(access_token.as_deref())?;
+        let res = request.headers(headers).send()?;
+        let status = res.status();
+        let retry_after = retry_after(res.headers());
+        let body = res.text()?;
+        parse_response(status, &body).map_err(|error| Failure {
+            error,
+            status: Some(status),
+            retry_after,
+        })
+    }
+
+    // Returns what a request to `method` with the prompt `texts` takes from the rate limiter.
+    fn cost(&self, method: Method, model: &str, texts: Vec<&str>) -> Result<Cost, PalmError> {
+        let tokens = match &self.config.rate_limiter {
+            Some(limiter) if limiter.counts_tokens_with_api(method) => {
+                let messages = texts.into_iter().map(str::to_string).collect();
+                self.count_message_tokens(model.to_string(), messages)?
+            }
+            Some(limiter) if limiter.limits_tokens(method) => estimate_tokens(texts),
+            _ => 0,
+        };
+        Ok(Cost { method, tokens })
+    }
+
+    // Waits until the rate limiter lets a request of `cost` through.
+    fn wait_for_quota(&self, cost: Cost) {
+        if let Some(limiter) = &self.config.rate_limiter {
+            while let Err(wait) = limiter.try_acquire(cost.method, cost.tokens) {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+
+    // Returns the access token of a service account or token provider.
+    fn access_token(&self) -> Result<Option<String>, Failure> {
+        match &self.config.auth {
+            #[cfg(feature = "service-account")]
+            Auth::ServiceAccount(account) => match account.cached_token() {
+                Some(token) => Ok(Some(token)),
+                None => self.refresh_token(account).map(Some),
+            },
+            Auth::Custom(provider) => Ok(Some(provider.access_token()?)),
+            _ => Ok(None),
+        }
+    }
+
+    // Fetches an access token, unless another thread fetched one while this one waited.
+    #[cfg(feature = "service-account")]
+    fn refresh_token(&self, account: &ServiceAccount) -> Result<String, Failure> {
+        let _refresh = account.refresh_lock();
+        if let Some(token) = account.cached_token() {
+            return Ok(token);
+        }
+        let (token_uri, form) = account.token_request()?;
+        let res = self.client.post(token_uri).form(&form).send()?;
+        let status = res.status();
+        let retry_after = retry_after(res.headers());
+        let body = res.text()?;
+        let token = parse_token_response(status, &body).map_err(|error| Failure {
+            error,
+            status: Some(status),
+            retry_after,
+        })?;
+        Ok(account.store_token(token))
     }
 
     /// Lists models available through the API.
@@ -308,47 +731,13 @@ impl PalmClient {
     ///     println!("{}",model.name);
     /// }
     /// ```
-    pub fn list_models(&self) -> Result<Vec<Model>, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .fetch_models()
-            .expect("Error occured while sending GET request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let parsed_models = self.parse_models(body)?;
-                return Ok(parsed_models.models);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
-            }
-        };
-    }
-
-    // functions for get_model
-    fn fetch_model(
-        &self,
-        model: &String,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let mut res = reqwest::blocking::get(format!(
-            "{}/v1beta2/models/{}?key={}",
-            self.endpoint, model, self.api_key
-        ))?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
-    }
-
-    fn parse_model(&self, body: String) -> serde_json::Result<Model> {
-        let parsed_model = serde_json::from_str(&body.as_str())?;
-        Ok(parsed_model)
+    pub fn list_models(&self) -> Result<Vec<Model>, PalmError> {
+        let parsed_models: ListRes = self.fetch(self.config.url("models"))?;
+        Ok(parsed_models.models)
     }
 
     /// Gets information about a specific Model.
+    /// Models are cached by the client, see `PalmClientBuilder::model_cache_ttl`.
     ///
     /// # Arguments
     /// * `model` - The resource name of the model
@@ -360,63 +749,28 @@ impl PalmClient {
     /// let model = client.get_model("text-bison-001".to_string()).expect("err");
     /// println!("{}",model.description);
     /// ```
-    pub fn get_model(&self, model: String) -> Result<Model, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .fetch_model(&model)
-            .expect("Error occured while sending GET request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let model = self.parse_model(body)?;
-                return Ok(model);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                return Err(format!("Model {} doesn't exist", &model).into());
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
-            }
-        };
+    pub fn get_model(&self, model: String) -> Result<Model, PalmError> {
+        if let Some(model_info) = self.config.model_cache.get(&model) {
+            return Ok(model_info);
+        }
+        let model_info = self.fetch(self.config.url(&format!("models/{}", model)))?;
+        self.config.model_cache.insert(&model, &model_info);
+        Ok(model_info)
     }
 
-    // functions for count_message_tokens
-    fn post_count_req(
-        &self,
-        model: &String,
-        message_list: Vec<String>,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let mut messages_vec: Vec<Message> = Vec::new();
-        for message_text in message_list {
-            let message = Message {
-                content: message_text,
-            };
-            messages_vec.push(message);
-        }
-        let messages = Messages {
-            messages: messages_vec,
-        };
-        let token_body = TokenBody { prompt: messages };
-        let client = reqwest::blocking::Client::new();
-        let mut res = client
-            .post(format!(
-                "{}/v1beta2/models/{}:countMessageTokens?key={}",
-                self.endpoint, model, self.api_key
-            ))
-            .json(&token_body)
-            .send()?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
+    /// Removes `model` from the model cache, so that the next `get_model()` fetches it again.
+    pub fn invalidate_model(&self, model: &str) {
+        self.config.model_cache.invalidate(Some(model));
+    }
+
+    /// Empties the model cache.
+    pub fn clear_model_cache(&self) {
+        self.config.model_cache.invalidate(None);
     }
 
-    fn parse_token(&self, body: String) -> serde_json::Result<TokenRes> {
-        let parsed_token = serde_json::from_str(&body.as_str())?;
-        Ok(parsed_token)
+    /// Returns the embedding cache set with `PalmClientBuilder::embedding_cache`, e.g. to read its statistics.
+    pub fn embedding_cache(&self) -> Option<&EmbeddingCache> {
+        self.config.embedding_cache.as_deref()
     }
 
     /// Runs a model's tokenizer on a string and returns the token count.
@@ -436,56 +790,32 @@ impl PalmClient {
         &self,
         model: String,
         message_list: Vec<String>,
-    ) -> Result<u32, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .post_count_req(&model, message_list)
-            .expect("Error occured while sending POST request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let parsed_token = self.parse_token(body)?;
-                return Ok(parsed_token.token_count);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                return Err(format!("Model {} not supported", &model).into());
-            }
-            reqwest::StatusCode::BAD_REQUEST => {
-                return Err("Message not found".to_string().into());
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
-            }
-        };
+    ) -> Result<u32, PalmError> {
+        self.count_tokens(model, &new_token_body(message_list))
     }
 
-    // functions for generate_embeddings
-    fn post_embed_req(
-        &self,
-        model: &String,
-        text: String,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let embed_body = EmbedBody { text: text };
-        let client = reqwest::blocking::Client::new();
-        let mut res = client
-            .post(format!(
-                "{}/v1beta2/models/{}:embedText?key={}",
-                self.endpoint, model, self.api_key
-            ))
-            .json(&embed_body)
-            .send()?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
-    }
-
-    fn parse_embeddings(&self, body: String) -> serde_json::Result<EmbedRes> {
-        let parsed_embeddings = serde_json::from_str(&body.as_str())?;
-        Ok(parsed_embeddings)
+    /// Runs a model's tokenizer on the whole prompt of `chat_body`, including its context and
+    /// examples, and returns the token count.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `chat_body` - The `ChatBody` that would be sent to `chat()`
+    pub fn count_chat_tokens(&self, model: String, chat_body: &ChatBody) -> Result<u32, PalmError> {
+        self.count_tokens(model, &chat_body.token_body())
+    }
+
+    fn count_tokens(&self, model: String, token_body: &TokenBody) -> Result<u32, PalmError> {
+        let cost = Cost {
+            method: Method::CountMessageTokens,
+            tokens: 0,
+        };
+        let parsed_token: TokenRes = self.post(
+            self.config
+                .url(&format!("models/{}:countMessageTokens", model)),
+            token_body,
+            cost,
+        )?;
+        Ok(parsed_token.token_count)
     }
 
     /// Generates an embedding from the model given an input message.
@@ -499,80 +829,110 @@ impl PalmClient {
     /// const API_KEY: &str = "";
     /// let client = palm_api::palm::create_client(API_KEY.to_string());
     /// let embeddings = client.generate_embeddings("embedding-gecko-001".to_string(),"say something nice!".to_string()).expect("err");
-    /// for embed_value in embeddings {
+    /// for embed_value in embeddings.iter() {
     ///     print!("{}, ",embed_value);
     /// }
     /// ```
-    pub fn generate_embeddings(
+    pub fn generate_embeddings(&self, model: String, text: String) -> Result<Embedding, PalmError> {
+        let Some(cache) = &self.config.embedding_cache else {
+            return self.embed_text(model, text);
+        };
+        if let Some(embedding) = cache.get(&model, &text) {
+            return Ok(embedding);
+        }
+        let embedding = self.embed_text(model, text.clone())?;
+        cache.insert(&text, &embedding);
+        Ok(embedding)
+    }
+
+    fn embed_text(&self, model: String, text: String) -> Result<Embedding, PalmError> {
+        let cost = self.cost(Method::EmbedText, &model, vec![text.as_str()])?;
+        let embed_body = EmbedBody { text };
+        let parsed_embeddings: EmbedRes = self.post(
+            self.config.url(&format!("models/{}:embedText", model)),
+            &embed_body,
+            cost,
+        )?;
+        Ok(Embedding::new(model, parsed_embeddings.embedding.value))
+    }
+
+    /// Generates an embedding for each text, sending up to 100 texts per request.
+    ///
+    /// The requests are sent from `PalmClientBuilder::batch_concurrency` threads, 4 by default.
+    ///
+    /// # Arguments
+    /// * `model` - The resource name of the model
+    /// * `texts` - The free-form input texts that the model will turn into embeddings
+    ///
+    /// Returns one result per text, in the order of `texts`.
+    /// A text of a failed request gets `PalmError::BatchFailed`; if the server rejects a request
+    /// (HTTP 400), its texts are embedded one by one so that only the invalid texts fail.
+    /// Texts found in the embedding cache are not sent.
+    ///
+    /// # Example
+    /// ```
+    /// let texts = vec!["say something nice!".to_string(), "say something else".to_string()];
+    /// for embeddings in client.batch_embed_texts("embedding-gecko-001".to_string(), texts) {
+    ///     println!("{:?}", embeddings.expect("err").to_vec());
+    /// }
+    /// ```
+    pub fn batch_embed_texts(
         &self,
         model: String,
-        text: String,
-    ) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .post_embed_req(&model, text)
-            .expect("Error occured while sending POST request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let parsed_embeddings = self.parse_embeddings(body)?;
-                return Ok(parsed_embeddings.embedding.value);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                return Err(format!("Model {} not supported", &model).into());
-            }
-            reqwest::StatusCode::BAD_REQUEST => {
-                return Err("Message not found".to_string().into());
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
-            }
+        texts: Vec<String>,
+    ) -> Vec<Result<Embedding, PalmError>> {
+        let Some(cache) = &self.config.embedding_cache else {
+            return self.embed_batch(model, texts);
         };
+        let (cached, misses) = cache.split(&model, texts);
+        let embedded = self.embed_batch(model, misses.clone());
+        cache.merge(cached, &misses, embedded)
     }
 
-    // functions for chat
-    fn post_chat_req(
-        &self,
-        model: &String,
-        chat_body: &mut ChatBody,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let model_info = self.get_model(model.to_string()).expect("err");
-        if chat_body.temperature == -1.0 {
-            chat_body.temperature = model_info.temperature.unwrap();
-        }
-        if chat_body.top_p == -1.0 {
-            chat_body.top_p = model_info.top_p.unwrap();
-        }
-        if chat_body.top_k == -1 {
-            chat_body.top_k = model_info.top_k.unwrap();
+    fn embed_batch(&self, model: String, texts: Vec<String>) -> Vec<EmbedResult> {
+        let queue = WorkQueue::chunks(texts);
+        let parts = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..queue.workers(self.config.batch_concurrency))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut parts = Vec::new();
+                        while let Some((offset, chunk)) = queue.next() {
+                            parts.push((offset, self.embed_chunk(&model, chunk)));
+                        }
+                        parts
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+        assemble(queue.len(), parts)
+    }
+
+    fn embed_chunk(&self, model: &str, texts: Vec<String>) -> Vec<EmbedResult> {
+        let texts_ref = texts.iter().map(String::as_str).collect();
+        let res = self
+            .cost(Method::BatchEmbedText, model, texts_ref)
+            .and_then(|cost| {
+                self.post(
+                    self.config.url(&format!("models/{}:batchEmbedText", model)),
+                    &BatchEmbedBody { texts: &texts },
+                    cost,
+                )
+            });
+        match res {
+            Err(PalmError::BadRequest(_)) if texts.len() > 1 => texts
+                .into_iter()
+                .map(|text| self.embed_text(model.to_string(), text))
+                .collect(),
+            res => chunk_results(model, texts.len(), res),
         }
-        let client = reqwest::blocking::Client::new();
-        let mut res = client
-            .post(format!(
-                "{}/v1beta2/models/{}:generateMessage?key={}",
-                self.endpoint, model, self.api_key
-            ))
-            .json(&chat_body)
-            .send()?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
-    }
-
-    fn parse_chat(
-        &self,
-        body: String,
-        chat_body: ChatBody,
-        model: &String,
-    ) -> serde_json::Result<ChatRes> {
-        let mut parsed_chat: ChatRes = serde_json::from_str(&body.as_str())?;
-        parsed_chat.chat_body = Some(chat_body);
-        parsed_chat.model = Some(model.to_string());
-        Ok(parsed_chat)
     }
 
     /// Generates a response from the model given an input ChatBody.
@@ -600,35 +960,18 @@ impl PalmClient {
     ///     .expect("err");
     /// println!("{}",chat_res.candidates.unwrap()[1].content);
     /// ```
-    pub fn chat(
-        &self,
-        model: String,
-        mut chat_body: ChatBody,
-    ) -> Result<ChatRes, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .post_chat_req(&model, &mut chat_body)
-            .expect("Error occured while sending POST request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let parsed_chats = self.parse_chat(body, chat_body, &model)?;
-                return Ok(parsed_chats);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                return Err(format!("Model {} not supported", &model).into());
-            }
-            reqwest::StatusCode::BAD_REQUEST => {
-                return Err("Bad Request".to_string().into());
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
-            }
-        };
+    pub fn chat(&self, model: String, chat_body: ChatBody) -> Result<ChatRes, PalmError> {
+        chat_body.validate()?;
+        let cost = self.cost(Method::GenerateMessage, &model, chat_body.prompt_texts())?;
+        let mut parsed_chat: ChatRes = self.post(
+            self.config
+                .url(&format!("models/{}:generateMessage", model)),
+            &chat_body,
+            cost,
+        )?;
+        parsed_chat.chat_body = Some(chat_body);
+        parsed_chat.model = Some(model);
+        Ok(parsed_chat)
     }
 
     /// Generates a response from the model given a previous response and a new message.
@@ -640,6 +983,9 @@ impl PalmClient {
     ///
     /// # Note
     /// * Sometimes, the model may return less candidates than specified in `candidate_count`. So, it is advised to either set `candidate_index` to 0 or check whether the candidate at `candidate_index` exists to avoid errors.
+    /// * The reply continues the history returned by the server, with the chosen candidate and `reply_message` appended; `reply_message` is sent with the author of the last message.
+    /// * Returns `PalmError::MissingCandidate` if there is no candidate at `candidate_index`.
+    ///
     /// # Example
     /// ```
     /// const API_KEY: &str = "";
@@ -667,50 +1013,62 @@ impl PalmClient {
         previous_response: ChatRes,
         reply_message: String,
         candidate_index: usize,
-    ) -> Result<ChatRes, Box<dyn std::error::Error>> {
-        let mut chat_body = previous_response.chat_body.unwrap();
-        chat_body.append_message(
-            previous_response.candidates.unwrap()[candidate_index]
-                .content
-                .clone(),
-        );
-        chat_body.append_message(reply_message);
-        let model = previous_response.model.unwrap();
+    ) -> Result<ChatRes, PalmError> {
+        let (model, chat_body) =
+            new_reply_body(&previous_response, reply_message, candidate_index)?;
         self.chat(model, chat_body)
     }
 
-    // functions for generate_text
-    fn post_text_req(
+    /// Replies to several candidates of a previous response in parallel, to explore different
+    /// branches of a conversation.
+    ///
+    /// Up to `PalmClientBuilder::branch_concurrency` replies, 4 by default, are sent at once.
+    ///
+    /// # Arguments
+    /// * `previous_response` - The previous `ChatRes` to whom the replies are being made
+    /// * `replies` - Pairs of a candidate index and the message replying to that candidate
+    ///
+    /// Returns one result per reply, in the order of `replies`.
+    ///
+    /// # Example
+    /// ```
+    /// let candidates = chat_res.candidates.as_ref().map_or(0, Vec::len);
+    /// let replies = (0..candidates).map(|index| (index, "Tell me more.".to_string())).collect();
+    /// for branch in client.branch(&chat_res, replies) {
+    ///     println!("{}", branch.expect("err").candidates.unwrap()[0].content);
+    /// }
+    /// ```
+    pub fn branch(
         &self,
-        model: &String,
-        mut text_body: TextBody,
-    ) -> Result<(reqwest::blocking::Response, String), Box<dyn std::error::Error>> {
-        let model_info = self.get_model(model.to_string()).expect("err");
-        if text_body.temperature == -1.0 {
-            text_body.set_temperature(model_info.temperature.unwrap());
-        }
-        if text_body.top_p == -1.0 {
-            text_body.set_top_p(model_info.top_p.unwrap());
-        }
-        if text_body.top_k == -1 {
-            text_body.set_top_k(model_info.top_k.unwrap());
-        }
-        let client = reqwest::blocking::Client::new();
-        let mut res = client
-            .post(format!(
-                "{}/v1beta2/models/{}:generateText?key={}",
-                self.endpoint, model, self.api_key
-            ))
-            .json(&text_body)
-            .send()?;
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        Ok((res, body))
-    }
-
-    fn parse_text(&self, body: String) -> serde_json::Result<TextRes> {
-        let parsed_text = serde_json::from_str(&body.as_str())?;
-        Ok(parsed_text)
+        previous_response: &ChatRes,
+        replies: Vec<(usize, String)>,
+    ) -> Vec<Result<ChatRes, PalmError>> {
+        let queue = WorkQueue::new(replies);
+        let parts = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..queue.workers(self.config.branch_concurrency))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut parts = Vec::new();
+                        while let Some((index, (candidate_index, reply_message))) = queue.next() {
+                            let result =
+                                new_reply_body(previous_response, reply_message, candidate_index)
+                                    .and_then(|(model, chat_body)| self.chat(model, chat_body));
+                            parts.push((index, vec![result]));
+                        }
+                        parts
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+        assemble(queue.len(), parts)
     }
 
     /// Generates a response from the model given an input message.
@@ -725,8 +1083,8 @@ impl PalmClient {
     /// let client = palm_api::palm::create_client(API_KEY.to_string());
     /// let mut text_body = palm_api::palm::new_text_body();
     /// text_body.append_safety_setting(
-    ///     "HARM_CATEGORY_TOXICITY".to_string(),
-    ///     "BLOCK_LOW_AND_ABOVE".to_string(),
+    ///     palm_api::palm::HarmCategory::Toxicity,
+    ///     palm_api::palm::HarmBlockThreshold::BlockLowAndAbove,
     /// );
     /// text_body.set_candidate_count(2);
     /// text_body.set_temperature(1.0);
@@ -739,32 +1097,46 @@ impl PalmClient {
     pub fn generate_text(
         &self,
         model: String,
-        text_body: TextBody,
-    ) -> Result<TextRes, Box<dyn std::error::Error>> {
-        let (res, body) = self
-            .post_text_req(&model, text_body)
-            .expect("Error occured while sending POST request");
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let parsed_text = self.parse_text(body)?;
-                return Ok(parsed_text);
-            }
-            reqwest::StatusCode::UNAUTHORIZED => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                panic!("API Key Invalid")
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                return Err(format!("Model {} not supported", &model).into());
-            }
-            reqwest::StatusCode::BAD_REQUEST => {
-                return Err("Bad Request".to_string().into());
-            }
-            other => {
-                panic!("Something unexpected happened: {}", other)
+        mut text_body: TextBody,
+    ) -> Result<TextRes, PalmError> {
+        text_body.validate()?;
+        if let Some(policy) = &self.config.safety_policy {
+            text_body.apply_safety_policy(policy);
+        }
+        let cost = self.cost(Method::GenerateText, &model, vec![text_body.text_prompt()])?;
+        self.post(
+            self.config.url(&format!("models/{}:generateText", model)),
+            &text_body,
+            cost,
+        )
+    }
+}
+
+// Checks the parameters shared by `TextBody` and `ChatBody`.
+fn validate_sampling(
+    temperature: Option<f64>,
+    candidate_count: Option<u32>,
+    top_p: Option<f64>,
+) -> Result<(), PalmError> {
+    for (name, value) in [("temperature", temperature), ("top_p", top_p)] {
+        match value {
+            Some(value) if !(0.0..=1.0).contains(&value) => {
+                return Err(PalmError::InvalidArgument(format!(
+                    "{} must be between 0 and 1, got {}",
+                    name, value
+                )))
             }
-        };
+            _ => {}
+        }
+    }
+    match candidate_count {
+        Some(count) if !(1..=MAX_CANDIDATE_COUNT).contains(&count) => {
+            Err(PalmError::InvalidArgument(format!(
+                "candidate_count must be between 1 and {}, got {}",
+                MAX_CANDIDATE_COUNT, count
+            )))
+        }
+        _ => Ok(()),
     }
 }
 
@@ -772,9 +1144,9 @@ fn new_message_prompt() -> MessagePrompt {
     let messages: Vec<Message> = Vec::new();
     let examples: Vec<Example> = Vec::new();
     MessagePrompt {
-        context: "".to_string(),
-        messages: messages,
-        examples: examples,
+        context: None,
+        messages,
+        examples,
     }
 }
 /// Creates a ChatBody struct.
@@ -782,6 +1154,8 @@ fn new_message_prompt() -> MessagePrompt {
 /// # Available methods
 /// * `append_example`
 /// * `append_message`
+/// * `push_message`
+/// * `set_messages`
 /// * `set_context`
 /// * `set_temperature`
 /// * `set_candidate_count`
@@ -789,28 +1163,85 @@ fn new_message_prompt() -> MessagePrompt {
 /// * `set_top_k`
 pub fn new_chat_body() -> ChatBody {
     let prompt = new_message_prompt();
-    let temperature = -1.0;
-    let candidate_count = 1;
-    let top_p = -1.0;
-    let top_k = -1;
+    let temperature = None;
+    let candidate_count = None;
+    let top_p = None;
+    let top_k = None;
     ChatBody {
-        prompt: prompt,
-        temperature: temperature,
-        candidate_count: candidate_count,
-        top_p: top_p,
-        top_k: top_k,
+        prompt,
+        temperature,
+        candidate_count,
+        top_p,
+        top_k,
     }
 }
 
 impl ChatBody {
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn token_body(&self) -> TokenBody {
+        TokenBody {
+            prompt: self.prompt.clone(),
+        }
+    }
+
+    /// Returns every text of the prompt: the context, the examples and the messages.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn prompt_texts(&self) -> Vec<&str> {
+        let examples = self
+            .prompt
+            .examples
+            .iter()
+            .flat_map(|example| [&example.input.content, &example.output.content]);
+        let messages = self.prompt.messages.iter().map(|message| &message.content);
+        self.prompt
+            .context
+            .iter()
+            .chain(examples)
+            .chain(messages)
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Checks that the parameters are within the ranges accepted by the API.
+    ///
+    /// Called by `chat()` before the request is sent.
+    pub fn validate(&self) -> Result<(), PalmError> {
+        validate_sampling(self.temperature, self.candidate_count, self.top_p)
+    }
+
+    /// Returns the context, if set.
+    pub fn context(&self) -> Option<&str> {
+        self.prompt.context.as_deref()
+    }
+
+    /// Returns the temperature, if set.
+    pub fn temperature(&self) -> Option<f64> {
+        self.temperature
+    }
+
+    /// Returns the candidate count, if set.
+    pub fn candidate_count(&self) -> Option<u32> {
+        self.candidate_count
+    }
+
+    /// Returns the top_p value, if set.
+    pub fn top_p(&self) -> Option<f64> {
+        self.top_p
+    }
+
+    /// Returns the top_k value, if set.
+    pub fn top_k(&self) -> Option<i32> {
+        self.top_k
+    }
+
     /// Appends an example to the existing list of examples.
     ///
     /// # Arguments
     /// * `input` - An example of an input Message from the user
     /// * `output` - An example of what the model should output given the input
     pub fn append_example(&mut self, input: String, output: String) {
-        let in_message = Message { content: input };
-        let out_message = Message { content: output };
+        let in_message = Message::new(input);
+        let out_message = Message::new(output);
         let example = Example {
             input: in_message,
             output: out_message,
@@ -823,16 +1254,36 @@ impl ChatBody {
     /// # Arguments
     /// * `content` - The text content of the structured Message
     pub fn append_message(&mut self, content: String) {
-        let message = Message { content: content };
+        self.prompt.messages.push(Message::new(content));
+    }
+
+    /// Appends a message with an explicit author to the existing list of messages.
+    ///
+    /// # Arguments
+    /// * `message` - The message, e.g. `Message::with_author("user", "Hello.")`
+    pub fn push_message(&mut self, message: Message) {
         self.prompt.messages.push(message);
     }
 
+    /// Replaces the messages with `messages`, e.g. the history of a previous `ChatRes`.
+    ///
+    /// # Arguments
+    /// * `messages` - The conversation history, in chronological order
+    pub fn set_messages(&mut self, messages: Vec<Message>) {
+        self.prompt.messages = messages;
+    }
+
+    /// Returns the messages of the conversation, in chronological order.
+    pub fn messages(&self) -> &[Message] {
+        &self.prompt.messages
+    }
+
     /// Sets context.
     ///
     /// # Arguments
     /// * `context` - Text that should be provided to the model first to ground the response
     pub fn set_context(&mut self, context: String) {
-        self.prompt.context = context;
+        self.prompt.context = Some(context);
     }
 
     /// Sets the temperature to be used by the model.
@@ -841,17 +1292,17 @@ impl ChatBody {
     /// # Arguments
     /// * `temperature` - Controls the randomness of the output
     pub fn set_temperature(&mut self, temperature: f64) {
-        self.temperature = temperature;
+        self.temperature = Some(temperature);
     }
 
     /// Sets the candidate count.
     /// Value between [1,8] inclusive.
-    /// Defaults to 1.
+    /// Defaults to 1 on the server.
     ///
     /// # Arguments
     /// * `candidate_count` - The number of generated response messages to return
     pub fn set_candidate_count(&mut self, candidate_count: u32) {
-        self.candidate_count = candidate_count;
+        self.candidate_count = Some(candidate_count);
     }
 
     /// Sets the top_p value to be used by the model.
@@ -860,7 +1311,7 @@ impl ChatBody {
     /// # Arguments
     /// * `top_p` - The maximum cumulative probability of tokens to consider when sampling
     pub fn set_top_p(&mut self, top_p: f64) {
-        self.top_p = top_p;
+        self.top_p = Some(top_p);
     }
 
     /// Sets the top_k value to be used by the model.
@@ -869,7 +1320,7 @@ impl ChatBody {
     /// # Arguments
     /// * `top_k` - The maximum number of tokens to consider when sampling
     pub fn set_top_k(&mut self, top_k: i32) {
-        self.top_k = top_k;
+        self.top_k = Some(top_k);
     }
 }
 
@@ -890,24 +1341,83 @@ pub fn new_text_body() -> TextBody {
     };
     let safety_settings: Vec<SafetySetting> = Vec::new();
     let stop_sequences: Vec<String> = Vec::new();
-    let temperature = -1.0;
-    let candidate_count = 1;
-    let max_output_tokens = 64;
-    let top_p = -1.0;
-    let top_k = -1;
+    let temperature = None;
+    let candidate_count = None;
+    let max_output_tokens = None;
+    let top_p = None;
+    let top_k = None;
     TextBody {
         prompt: text_prompt,
-        safety_settings: safety_settings,
-        stop_sequences: stop_sequences,
-        temperature: temperature,
-        candidate_count: candidate_count,
-        max_output_tokens: max_output_tokens,
-        top_p: top_p,
-        top_k: top_k,
+        safety_settings,
+        stop_sequences,
+        temperature,
+        candidate_count,
+        max_output_tokens,
+        top_p,
+        top_k,
     }
 }
 
 impl TextBody {
+    /// Checks that the parameters are within the ranges accepted by the API.
+    ///
+    /// Called by `generate_text()` before the request is sent.
+    pub fn validate(&self) -> Result<(), PalmError> {
+        if self.stop_sequences.len() > MAX_STOP_SEQUENCES {
+            return Err(PalmError::InvalidArgument(format!(
+                "at most {} stop sequences are allowed, got {}",
+                MAX_STOP_SEQUENCES,
+                self.stop_sequences.len()
+            )));
+        }
+        validate_sampling(self.temperature, self.candidate_count, self.top_p)
+    }
+
+    /// Returns the prompt text.
+    pub fn text_prompt(&self) -> &str {
+        &self.prompt.text
+    }
+
+    /// Returns the safety settings.
+    pub fn safety_settings(&self) -> &[SafetySetting] {
+        &self.safety_settings
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn apply_safety_policy(&mut self, policy: &SafetyPolicy) {
+        policy.merge_into(&mut self.safety_settings);
+    }
+
+    /// Returns the stop sequences.
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
+    /// Returns the temperature, if set.
+    pub fn temperature(&self) -> Option<f64> {
+        self.temperature
+    }
+
+    /// Returns the candidate count, if set.
+    pub fn candidate_count(&self) -> Option<u32> {
+        self.candidate_count
+    }
+
+    /// Returns the max_output_tokens value, if set.
+    pub fn max_output_tokens(&self) -> Option<u32> {
+        self.max_output_tokens
+    }
+
+    /// Returns the top_p value, if set.
+    pub fn top_p(&self) -> Option<f64> {
+        self.top_p
+    }
+
+    /// Returns the top_k value, if set.
+    pub fn top_k(&self) -> Option<i32> {
+        self.top_k
+    }
+
     /// Set the free-form input text given to the model as a prompt.
     ///
     /// # Arguments
@@ -919,14 +1429,12 @@ impl TextBody {
     /// Append a unique SafetySetting instance for blocking unsafe content.
     ///
     /// # Arguments
-    /// * `category` - These categories cover various kinds of harms that developers may wish to adjust,
-    /// `category` can have the following values: HARM_CATEGORY_UNSPECIFIED, HARM_CATEGORY_DEROGATORY, HARM_CATEGORY_TOXICITY, HARM_CATEGORY_VIOLENCE, HARM_CATEGORY_SEXUAL, HARM_CATEGORY_MEDICAL, HARM_CATEGORY_DANGEROUS
+    /// * `category` - The kind of harm the setting applies to
     /// * `threshold` - Block at and beyond a specified harm probability
-    /// `threshold` can have the following values: HARM_BLOCK_THRESHOLD_UNSPECIFIED, BLOCK_LOW_AND_ABOVE, BLOCK_MEDIUM_AND_ABOVE, BLOCK_ONLY_HIGH, BLOCK_NONE
-    pub fn append_safety_setting(&mut self, category: String, threshold: String) {
+    pub fn append_safety_setting(&mut self, category: HarmCategory, threshold: HarmBlockThreshold) {
         self.safety_settings.push(SafetySetting {
-            category: category,
-            threshold: threshold,
+            category,
+            threshold,
         });
     }
 
@@ -946,26 +1454,26 @@ impl TextBody {
     /// # Arguments
     /// * `temperature` - Controls the randomness of the output
     pub fn set_temperature(&mut self, temperature: f64) {
-        self.temperature = temperature;
+        self.temperature = Some(temperature);
     }
 
     /// Sets the candidate count.
     /// Value between [1,8] inclusive.
-    /// Defaults to 1.
+    /// Defaults to 1 on the server.
     ///
     /// # Arguments
     /// * `candidate_count` - Number of generated responses to return
     pub fn set_candidate_count(&mut self, candidate_count: u32) {
-        self.candidate_count = candidate_count;
+        self.candidate_count = Some(candidate_count);
     }
 
     /// Sets the max_output_tokens value to be used by model.
-    /// Defaults to 64.
+    /// Defaults to model value.
     ///
     /// # Arguments
     /// * `max_output_tokens` - The maximum number of tokens to include in a candidate
     pub fn set_max_output_tokens(&mut self, max_output_tokens: u32) {
-        self.max_output_tokens = max_output_tokens;
+        self.max_output_tokens = Some(max_output_tokens);
     }
 
     /// Sets the top_p value to be used by the model.
@@ -974,7 +1482,7 @@ impl TextBody {
     /// # Arguments
     /// * `top_p` - The maximum cumulative probability of tokens to consider when sampling
     pub fn set_top_p(&mut self, top_p: f64) {
-        self.top_p = top_p;
+        self.top_p = Some(top_p);
     }
 
     /// Sets the top_k value to be used by the model.
@@ -983,6 +1491,179 @@ impl TextBody {
     /// # Arguments
     /// * `top_k` - The maximum number of tokens to consider when sampling
     pub fn set_top_k(&mut self, top_k: i32) {
-        self.top_k = top_k;
+        self.top_k = Some(top_k);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        new_chat_body, new_text_body, BlockedReason, ChatRes, HarmBlockThreshold, HarmCategory,
+        HarmProbability, Message, PalmError, SafetyRating,
+    };
+
+    #[test]
+    fn unset_parameters_are_not_serialized() {
+        let mut text_body = new_text_body();
+        text_body.set_text_prompt("The opposite of hot is".to_string());
+        assert_eq!(
+            serde_json::to_string(&text_body).expect("err"),
+            r#"{"prompt":{"text":"The opposite of hot is"}}"#
+        );
+        let mut chat_body = new_chat_body();
+        chat_body.append_message("Hello.".to_string());
+        chat_body.set_candidate_count(2);
+        assert_eq!(
+            serde_json::to_string(&chat_body).expect("err"),
+            r#"{"prompt":{"messages":[{"content":"Hello."}]},"candidateCount":2}"#
+        );
+        assert_eq!(chat_body.candidate_count(), Some(2));
+        assert_eq!(chat_body.temperature(), None);
+    }
+
+    #[test]
+    fn validates_parameter_ranges() {
+        let mut text_body = new_text_body();
+        text_body.set_temperature(1.0);
+        text_body.set_candidate_count(8);
+        assert!(text_body.validate().is_ok());
+        text_body.set_temperature(1.5);
+        assert!(matches!(
+            text_body.validate(),
+            Err(PalmError::InvalidArgument(_))
+        ));
+
+        let mut text_body = new_text_body();
+        for stop_sequence in ["a", "b", "c", "d", "e", "f"] {
+            text_body.append_stop_sequence(stop_sequence.to_string());
+        }
+        assert!(text_body.validate().is_err());
+
+        let mut chat_body = new_chat_body();
+        chat_body.set_candidate_count(0);
+        assert!(chat_body.validate().is_err());
+    }
+
+    #[test]
+    fn harm_enums_use_api_names() {
+        let mut text_body = new_text_body();
+        text_body.append_safety_setting(HarmCategory::Toxicity, HarmBlockThreshold::BlockOnlyHigh);
+        assert_eq!(
+            serde_json::to_string(text_body.safety_settings()).expect("err"),
+            r#"[{"category":"HARM_CATEGORY_TOXICITY","threshold":"BLOCK_ONLY_HIGH"}]"#
+        );
+        let rating: SafetyRating =
+            serde_json::from_str(r#"{"category": "HARM_CATEGORY_NEW", "probability": "MEDIUM"}"#)
+                .expect("err");
+        assert_eq!(rating.category, HarmCategory::Unknown);
+        assert!(rating.probability > HarmProbability::Low);
+        assert!(rating.probability < HarmProbability::High);
+        assert!(HarmProbability::Unknown < HarmProbability::Negligible);
+
+        let chat_res: ChatRes = serde_json::from_str(
+            r#"{"messages": [], "filters": [{"reason": "SAFETY", "message": "blocked"},
+                {"reason": "OTHER"}]}"#,
+        )
+        .expect("err");
+        let filters = chat_res.filters.unwrap();
+        assert_eq!(filters[0].reason, BlockedReason::Safety);
+        assert_eq!(filters[0].message.as_deref(), Some("blocked"));
+        assert_eq!(filters[1].reason, BlockedReason::Other);
+    }
+
+    #[test]
+    fn messages_keep_authors_through_history() {
+        let chat_res: ChatRes = serde_json::from_str(
+            r#"{"messages": [{"author": "user", "content": "Hello."}],
+                "candidates": [{"author": "assistant", "content": "Hi!",
+                    "citationMetadata": {"citationSources": [{"startIndex": 0, "endIndex": 3}]}}]}"#,
+        )
+        .expect("err");
+        let candidate = &chat_res.candidates.as_ref().unwrap()[0];
+        assert_eq!(candidate.author, "assistant");
+        let sources = &candidate
+            .citation_metadata
+            .as_ref()
+            .unwrap()
+            .citation_sources;
+        assert_eq!(sources[0].end_index, Some(3));
+
+        let mut chat_body = new_chat_body();
+        chat_body.set_messages(chat_res.messages);
+        chat_body.push_message(Message::with_author("user", "What can you do?"));
+        chat_body.append_message("Anything else?".to_string());
+        assert_eq!(
+            serde_json::to_value(chat_body.messages()).expect("err"),
+            serde_json::json!([
+                {"author": "user", "content": "Hello."},
+                {"author": "user", "content": "What can you do?"},
+                {"content": "Anything else?"}
+            ])
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn reply_and_branch_continue_server_history() {
+        use super::{ChatRequest, PalmClient};
+        use crate::test_server::{Reply, TestServer};
+
+        let response = r#"{"messages": [{"author": "0", "content": "Hello."}],
+            "candidates": [{"author": "1", "content": "Hi!"}, {"author": "1", "content": "Hey!"}]}"#;
+        let server = TestServer::start(vec![
+            Reply::json(200, response),
+            Reply::json(200, response),
+            Reply::json(200, response),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let chat_request = ChatRequest::builder()
+            .message("Hello.")
+            .candidate_count(2)
+            .build()
+            .expect("err");
+        let chat_res = client
+            .chat("chat-bison-001".to_string(), chat_request)
+            .expect("err");
+
+        let branches = client.branch(
+            &chat_res,
+            vec![
+                (0, "Why?".to_string()),
+                (1, "How?".to_string()),
+                (2, "What?".to_string()),
+            ],
+        );
+        assert!(branches[0].is_ok() && branches[1].is_ok());
+        assert!(matches!(
+            branches[2],
+            Err(PalmError::MissingCandidate {
+                index: 2,
+                available: 2
+            })
+        ));
+        let mut sent: Vec<serde_json::Value> = server.requests()[1..]
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = serde_json::from_str(&request.body).expect("err");
+                body["prompt"]["messages"].clone()
+            })
+            .collect();
+        sent.sort_by_key(|messages| messages[1]["content"].to_string());
+        assert_eq!(
+            sent[1],
+            serde_json::json!([
+                {"author": "0", "content": "Hello."},
+                {"author": "1", "content": "Hi!"},
+                {"author": "0", "content": "Why?"}
+            ])
+        );
+        assert_eq!(sent[0][1]["content"], "Hey!");
+
+        let err = client.reply(chat_res, "Where?".to_string(), 7).unwrap_err();
+        assert!(matches!(err, PalmError::MissingCandidate { index: 7, .. }));
+        assert_eq!(server.requests().len(), 3);
     }
 }
@@ -0,0 +1,287 @@
+//! Client-side rate limiting, keeping requests within the per-minute quotas of the API.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+// Rough ratio of characters per token for English text, used when tokens are not counted by the API.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// An API method with its own quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// `generateText`, used by `generate_text()`.
+    GenerateText,
+    /// `generateMessage`, used by `chat()` and `reply()`.
+    GenerateMessage,
+    /// `embedText`, used by `generate_embeddings()`.
+    EmbedText,
+    /// `batchEmbedText`, used by `batch_embed_texts()`.
+    BatchEmbedText,
+    /// `countMessageTokens`, used by `count_message_tokens()`.
+    CountMessageTokens,
+}
+
+/// What a request takes from the rate limiter, before each of its attempts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cost {
+    pub(crate) method: Method,
+    pub(crate) tokens: u32,
+}
+
+/// The per-minute limits of a method.
+///
+/// # Example
+/// ```
+/// let quota = palm_api::rate_limit::Quota::requests_per_minute(90).and_tokens_per_minute(20_000);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    /// The maximum number of requests per minute.
+    pub requests_per_minute: Option<u32>,
+    /// The maximum number of prompt tokens per minute.
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl Quota {
+    /// Creates a quota limiting the number of requests per minute.
+    pub fn requests_per_minute(requests: u32) -> Quota {
+        Quota {
+            requests_per_minute: Some(requests),
+            tokens_per_minute: None,
+        }
+    }
+
+    /// Creates a quota limiting the number of prompt tokens per minute.
+    pub fn tokens_per_minute(tokens: u32) -> Quota {
+        Quota {
+            requests_per_minute: None,
+            tokens_per_minute: Some(tokens),
+        }
+    }
+
+    /// Also limits the number of prompt tokens per minute.
+    pub fn and_tokens_per_minute(mut self, tokens: u32) -> Quota {
+        self.tokens_per_minute = Some(tokens);
+        self
+    }
+}
+
+/// A bucket holding up to a minute worth of capacity, refilled continuously.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Bucket {
+        Bucket {
+            capacity: per_minute as f64,
+            available: per_minute as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.available =
+            (self.available + elapsed * self.capacity / MINUTE.as_secs_f64()).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Returns how long to wait until `amount` is available.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing * MINUTE.as_secs_f64() / self.capacity)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// Token-bucket rate limiter shared by every clone of a client.
+///
+/// Requests wait until the quota of their method has capacity, blocking the thread with
+/// `PalmClient` and yielding to the runtime with `AsyncPalmClient`. Every attempt of a retried
+/// request takes from the quota again.
+/// Prompt tokens are estimated from the prompt length, unless `count_tokens_with_api` is set.
+///
+/// # Example
+/// ```
+/// use palm_api::rate_limit::{Method, Quota, RateLimiter};
+///
+/// let limiter = RateLimiter::new()
+///     .quota(Method::GenerateText, Quota::requests_per_minute(90))
+///     .quota(Method::EmbedText, Quota::requests_per_minute(600));
+/// let client = palm_api::palm::PalmClient::builder()
+///     .api_key(API_KEY)
+///     .rate_limiter(limiter)
+///     .build()
+///     .expect("err");
+/// ```
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    quotas: HashMap<Method, Quota>,
+    count_tokens_with_api: bool,
+    buckets: Mutex<HashMap<Method, Buckets>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter without any quota.
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Sets the quota of `method`.
+    pub fn quota(mut self, method: Method, quota: Quota) -> RateLimiter {
+        self.quotas.insert(method, quota);
+        self
+    }
+
+    /// Counts the prompt tokens of `generateMessage` requests with `count_message_tokens()`
+    /// instead of estimating them. The extra request is subject to its own quota.
+    pub fn count_tokens_with_api(mut self, count_tokens_with_api: bool) -> RateLimiter {
+        self.count_tokens_with_api = count_tokens_with_api;
+        self
+    }
+
+    /// Returns whether requests to `method` are limited by the number of tokens.
+    pub(crate) fn limits_tokens(&self, method: Method) -> bool {
+        self.quotas
+            .get(&method)
+            .is_some_and(|quota| quota.tokens_per_minute.is_some())
+    }
+
+    /// Returns whether the tokens of `method` should be counted by the API.
+    pub(crate) fn counts_tokens_with_api(&self, method: Method) -> bool {
+        self.count_tokens_with_api
+            && method == Method::GenerateMessage
+            && self.limits_tokens(method)
+    }
+
+    /// Takes one request and `tokens` from the quota of `method`, or returns how long to wait
+    /// until they are available. A request larger than the token quota waits for a full bucket.
+    pub(crate) fn try_acquire(&self, method: Method, tokens: u32) -> Result<(), Duration> {
+        let Some(quota) = self.quotas.get(&method) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        let buckets = buckets.entry(method).or_default();
+        let requests = quota.requests_per_minute.map(|limit| {
+            buckets
+                .requests
+                .get_or_insert_with(|| Bucket::new(limit, now))
+        });
+        let tokens_bucket = quota.tokens_per_minute.map(|limit| {
+            buckets
+                .tokens
+                .get_or_insert_with(|| Bucket::new(limit, now))
+        });
+        let mut wait = Duration::ZERO;
+        let mut claims = Vec::new();
+        for (bucket, amount) in [(requests, 1.0), (tokens_bucket, tokens as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                wait = wait.max(bucket.wait_for(amount));
+                claims.push((bucket, amount));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (bucket, amount) in claims {
+            bucket.take(amount);
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the number of tokens of `texts`.
+pub(crate) fn estimate_tokens<'a>(texts: impl IntoIterator<Item = &'a str>) -> u32 {
+    let chars: usize = texts.into_iter().map(|text| text.chars().count()).sum();
+    chars.div_ceil(CHARS_PER_TOKEN) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_tokens, Method, Quota, RateLimiter};
+    use std::time::Duration;
+
+    #[test]
+    fn limits_requests_per_minute() {
+        let limiter = RateLimiter::new().quota(Method::GenerateText, Quota::requests_per_minute(2));
+        assert_eq!(limiter.try_acquire(Method::GenerateText, 0), Ok(()));
+        assert_eq!(limiter.try_acquire(Method::GenerateText, 0), Ok(()));
+        let wait = limiter.try_acquire(Method::GenerateText, 0).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        // Methods without a quota are never limited.
+        assert_eq!(limiter.try_acquire(Method::EmbedText, 0), Ok(()));
+    }
+
+    #[test]
+    fn limits_tokens_per_minute() {
+        let limiter = RateLimiter::new().quota(
+            Method::GenerateMessage,
+            Quota::requests_per_minute(100).and_tokens_per_minute(120),
+        );
+        assert!(limiter.limits_tokens(Method::GenerateMessage));
+        assert_eq!(limiter.try_acquire(Method::GenerateMessage, 100), Ok(()));
+        let wait = limiter
+            .try_acquire(Method::GenerateMessage, 30)
+            .unwrap_err();
+        assert!(wait > Duration::from_secs(4) && wait <= Duration::from_secs(5));
+        // A rejected request takes nothing from the buckets.
+        assert_eq!(limiter.try_acquire(Method::GenerateMessage, 20), Ok(()));
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn retries_take_quota() {
+        use crate::palm::PalmClient;
+        use crate::test_server::{Reply, TestServer};
+        use std::time::Instant;
+
+        let server = TestServer::start(vec![
+            Reply::json(503, r#"{"error": {"code": 503, "message": "overloaded"}}"#)
+                .header("Retry-After", "0"),
+            Reply::json(200, r#"{"embedding": {"value": [0.5]}}"#),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .rate_limiter(
+                RateLimiter::new().quota(Method::EmbedText, Quota::requests_per_minute(600)),
+            )
+            .build()
+            .expect("err");
+        // One request left in the bucket, then one more every 100ms.
+        let limiter = client.config.rate_limiter.as_ref().expect("err");
+        for _ in 0..599 {
+            assert_eq!(limiter.try_acquire(Method::EmbedText, 0), Ok(()));
+        }
+        let started = Instant::now();
+        client
+            .generate_embeddings("embedding-gecko-001".to_string(), "hello".to_string())
+            .expect("err");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn estimates_tokens_from_length() {
+        assert_eq!(estimate_tokens(["", "abcd", "e"]), 2);
+    }
+}
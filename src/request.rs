@@ -0,0 +1,270 @@
+//! Fluent builders for the request bodies of `generate_text()` and `chat()`.
+
+use crate::palm::{
+    new_chat_body, new_text_body, ChatBody, HarmBlockThreshold, HarmCategory, Message, PalmError,
+    TextBody,
+};
+
+/// The request of `generate_text()`, built with `TextRequest::builder()`.
+pub type TextRequest = TextBody;
+
+/// The request of `chat()`, built with `ChatRequest::builder()`.
+pub type ChatRequest = ChatBody;
+
+/// Builds a `TextRequest`, checking its parameters in `build()`.
+///
+/// Builders are cheap to clone, so a template can be kept around and variants derived from it.
+///
+/// # Example
+/// ```
+/// use palm_api::palm::TextRequest;
+///
+/// let template = TextRequest::builder().temperature(0.2).max_output_tokens(256);
+/// let text_request = template
+///     .clone()
+///     .prompt("Write a story about a magic backpack.")
+///     .build()
+///     .expect("err");
+/// let text_res = client
+///     .generate_text("text-bison-001".to_string(), text_request)
+///     .expect("err");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextRequestBuilder {
+    body: TextBody,
+}
+
+impl Default for TextRequestBuilder {
+    fn default() -> TextRequestBuilder {
+        TextRequestBuilder {
+            body: new_text_body(),
+        }
+    }
+}
+
+impl TextBody {
+    /// Returns a `TextRequestBuilder`.
+    pub fn builder() -> TextRequestBuilder {
+        TextRequestBuilder::default()
+    }
+
+    /// Returns a `TextRequestBuilder` starting from this request, to derive a variant of it.
+    pub fn into_builder(self) -> TextRequestBuilder {
+        TextRequestBuilder { body: self }
+    }
+}
+
+impl TextRequestBuilder {
+    /// Sets the free-form input text given to the model as a prompt.
+    pub fn prompt(mut self, text: impl Into<String>) -> TextRequestBuilder {
+        self.body.set_text_prompt(text.into());
+        self
+    }
+
+    /// Appends a safety setting, see `TextBody::append_safety_setting`.
+    pub fn safety_setting(
+        mut self,
+        category: HarmCategory,
+        threshold: HarmBlockThreshold,
+    ) -> TextRequestBuilder {
+        self.body.append_safety_setting(category, threshold);
+        self
+    }
+
+    /// Appends a stop sequence; up to 5 stop sequences are allowed.
+    pub fn stop_sequence(mut self, stop_sequence: impl Into<String>) -> TextRequestBuilder {
+        self.body.append_stop_sequence(stop_sequence.into());
+        self
+    }
+
+    /// Sets the temperature, between 0 and 1.
+    pub fn temperature(mut self, temperature: f64) -> TextRequestBuilder {
+        self.body.set_temperature(temperature);
+        self
+    }
+
+    /// Sets the number of responses to return, between 1 and 8.
+    pub fn candidate_count(mut self, candidate_count: u32) -> TextRequestBuilder {
+        self.body.set_candidate_count(candidate_count);
+        self
+    }
+
+    /// Sets the maximum number of tokens to include in a candidate.
+    pub fn max_output_tokens(mut self, max_output_tokens: u32) -> TextRequestBuilder {
+        self.body.set_max_output_tokens(max_output_tokens);
+        self
+    }
+
+    /// Sets the maximum cumulative probability of tokens to consider when sampling.
+    pub fn top_p(mut self, top_p: f64) -> TextRequestBuilder {
+        self.body.set_top_p(top_p);
+        self
+    }
+
+    /// Sets the maximum number of tokens to consider when sampling.
+    pub fn top_k(mut self, top_k: i32) -> TextRequestBuilder {
+        self.body.set_top_k(top_k);
+        self
+    }
+
+    /// Builds the `TextRequest`, or returns `PalmError::InvalidArgument` if a parameter is out of range.
+    pub fn build(self) -> Result<TextRequest, PalmError> {
+        self.body.validate()?;
+        Ok(self.body)
+    }
+}
+
+/// Builds a `ChatRequest`, checking its parameters in `build()`.
+///
+/// # Example
+/// ```
+/// use palm_api::palm::ChatRequest;
+///
+/// let chat_request = ChatRequest::builder()
+///     .context("Reply in english")
+///     .example("How are you doing?", "I am doing absolutely fine!")
+///     .message("How are you doing?")
+///     .temperature(0.8)
+///     .build()
+///     .expect("err");
+/// let chat_res = client
+///     .chat("chat-bison-001".to_string(), chat_request)
+///     .expect("err");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatRequestBuilder {
+    body: ChatBody,
+}
+
+impl Default for ChatRequestBuilder {
+    fn default() -> ChatRequestBuilder {
+        ChatRequestBuilder {
+            body: new_chat_body(),
+        }
+    }
+}
+
+impl ChatBody {
+    /// Returns a `ChatRequestBuilder`.
+    pub fn builder() -> ChatRequestBuilder {
+        ChatRequestBuilder::default()
+    }
+
+    /// Returns a `ChatRequestBuilder` starting from this request, to derive a variant of it.
+    pub fn into_builder(self) -> ChatRequestBuilder {
+        ChatRequestBuilder { body: self }
+    }
+}
+
+impl ChatRequestBuilder {
+    /// Sets the text that should be provided to the model first to ground the response.
+    pub fn context(mut self, context: impl Into<String>) -> ChatRequestBuilder {
+        self.body.set_context(context.into());
+        self
+    }
+
+    /// Appends an example of an input and of what the model should output given it.
+    pub fn example(
+        mut self,
+        input: impl Into<String>,
+        output: impl Into<String>,
+    ) -> ChatRequestBuilder {
+        self.body.append_example(input.into(), output.into());
+        self
+    }
+
+    /// Appends a message to the conversation.
+    pub fn message(mut self, content: impl Into<String>) -> ChatRequestBuilder {
+        self.body.append_message(content.into());
+        self
+    }
+
+    /// Appends a message written by `author`.
+    pub fn author_message(
+        mut self,
+        author: impl Into<String>,
+        content: impl Into<String>,
+    ) -> ChatRequestBuilder {
+        self.body
+            .push_message(Message::with_author(author, content));
+        self
+    }
+
+    /// Appends every message of `messages`, e.g. the history of a previous `ChatRes`.
+    pub fn messages(mut self, messages: impl IntoIterator<Item = Message>) -> ChatRequestBuilder {
+        for message in messages {
+            self.body.push_message(message);
+        }
+        self
+    }
+
+    /// Sets the temperature, between 0 and 1.
+    pub fn temperature(mut self, temperature: f64) -> ChatRequestBuilder {
+        self.body.set_temperature(temperature);
+        self
+    }
+
+    /// Sets the number of response messages to return, between 1 and 8.
+    pub fn candidate_count(mut self, candidate_count: u32) -> ChatRequestBuilder {
+        self.body.set_candidate_count(candidate_count);
+        self
+    }
+
+    /// Sets the maximum cumulative probability of tokens to consider when sampling.
+    pub fn top_p(mut self, top_p: f64) -> ChatRequestBuilder {
+        self.body.set_top_p(top_p);
+        self
+    }
+
+    /// Sets the maximum number of tokens to consider when sampling.
+    pub fn top_k(mut self, top_k: i32) -> ChatRequestBuilder {
+        self.body.set_top_k(top_k);
+        self
+    }
+
+    /// Builds the `ChatRequest`, or returns `PalmError::InvalidArgument` if a parameter is out of range.
+    pub fn build(self) -> Result<ChatRequest, PalmError> {
+        self.body.validate()?;
+        Ok(self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatRequest, TextRequest};
+    use crate::palm::{new_text_body, PalmError};
+
+    #[test]
+    fn builds_the_same_body_as_setters() {
+        let text_request = TextRequest::builder()
+            .prompt("The opposite of hot is")
+            .stop_sequence(".")
+            .temperature(0.5)
+            .build()
+            .expect("err");
+        let mut text_body = new_text_body();
+        text_body.set_text_prompt("The opposite of hot is".to_string());
+        text_body.append_stop_sequence(".".to_string());
+        text_body.set_temperature(0.5);
+        assert_eq!(text_request, text_body);
+
+        let variant = text_request
+            .clone()
+            .into_builder()
+            .temperature(0.9)
+            .build()
+            .expect("err");
+        assert_ne!(variant, text_request);
+        assert_eq!(variant.text_prompt(), "The opposite of hot is");
+    }
+
+    #[test]
+    fn build_validates() {
+        let template = ChatRequest::builder().context("Reply in english");
+        assert!(template.clone().message("Hello.").build().is_ok());
+        assert!(matches!(
+            template.candidate_count(9).build(),
+            Err(PalmError::InvalidArgument(_))
+        ));
+    }
+}
@@ -0,0 +1,329 @@
+//! Retrying transient failures with exponential backoff.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+
+use crate::error::PalmError;
+
+/// A failed attempt about to be retried, passed to the hook set with `RetryPolicy::on_retry`.
+#[derive(Debug)]
+pub struct RetryAttempt<'a> {
+    /// The number of the attempt that failed, starting at 1.
+    pub attempt: u32,
+    /// How long the client waits before the next attempt.
+    pub delay: Duration,
+    /// The error of the failed attempt.
+    pub error: &'a PalmError,
+}
+
+type RetryHook = Arc<dyn Fn(&RetryAttempt) + Send + Sync>;
+
+/// Decides which failed requests are retried, and how long to wait between attempts.
+///
+/// The delay doubles after every attempt, starting at `base_delay` and capped at `max_delay`.
+/// A delay requested by the server, through the `Retry-After` header or a `RetryInfo` detail,
+/// takes precedence; the request is not retried if that delay exceeds `max_delay`.
+///
+/// # Example
+/// ```
+/// use palm_api::retry::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::new()
+///     .max_attempts(5)
+///     .base_delay(Duration::from_millis(250))
+///     .on_retry(|attempt| eprintln!("attempt {} failed: {}", attempt.attempt, attempt.error));
+/// let client = palm_api::palm::PalmClient::builder()
+///     .api_key(API_KEY)
+///     .retry_policy(policy)
+///     .build()
+///     .expect("err");
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    retryable_statuses: Vec<u16>,
+    retry_on_timeout: bool,
+    retry_on_connect: bool,
+    honor_retry_after: bool,
+    on_retry: Option<RetryHook>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new()
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .field("retryable_statuses", &self.retryable_statuses)
+            .field("retry_on_timeout", &self.retry_on_timeout)
+            .field("retry_on_connect", &self.retry_on_connect)
+            .field("honor_retry_after", &self.honor_retry_after)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// Creates the default policy: up to 3 attempts, retrying 429, 500, 502, 503 and 504
+    /// responses, timeouts and connection failures, waiting from 500ms up to 30s.
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            retryable_statuses: vec![429, 500, 502, 503, 504],
+            retry_on_timeout: true,
+            retry_on_connect: true,
+            honor_retry_after: true,
+            on_retry: None,
+        }
+    }
+
+    /// Creates a policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new().max_attempts(1)
+    }
+
+    /// Sets the maximum number of attempts, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry.
+    pub fn base_delay(mut self, base_delay: Duration) -> RetryPolicy {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Sets the longest delay between two attempts.
+    pub fn max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Randomizes each backoff delay between half and all of its value, so that clients
+    /// failing together do not retry together.
+    /// Defaults to `true`.
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the HTTP status codes that are retried.
+    pub fn retryable_statuses(mut self, statuses: Vec<u16>) -> RetryPolicy {
+        self.retryable_statuses = statuses;
+        self
+    }
+
+    /// Sets whether requests that timed out are retried.
+    pub fn retry_on_timeout(mut self, retry_on_timeout: bool) -> RetryPolicy {
+        self.retry_on_timeout = retry_on_timeout;
+        self
+    }
+
+    /// Sets whether requests that failed to connect are retried.
+    pub fn retry_on_connect(mut self, retry_on_connect: bool) -> RetryPolicy {
+        self.retry_on_connect = retry_on_connect;
+        self
+    }
+
+    /// Sets whether the delay requested by the server is honored.
+    /// Defaults to `true`.
+    pub fn honor_retry_after(mut self, honor_retry_after: bool) -> RetryPolicy {
+        self.honor_retry_after = honor_retry_after;
+        self
+    }
+
+    /// Calls `hook` before every retry.
+    pub fn on_retry(mut self, hook: impl Fn(&RetryAttempt) + Send + Sync + 'static) -> RetryPolicy {
+        self.on_retry = Some(Arc::new(hook));
+        self
+    }
+
+    /// Returns how long to wait before retrying after `attempt` failed with `error`, or `None`
+    /// if the request must not be retried. Calls the retry hook when retrying.
+    pub(crate) fn next_delay(
+        &self,
+        attempt: u32,
+        error: &PalmError,
+        status: Option<StatusCode>,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(error, status) {
+            return None;
+        }
+        let requested = match self.honor_retry_after {
+            true => retry_after.or_else(|| {
+                error
+                    .api_error()
+                    .and_then(|api_error| api_error.retry_info())
+                    .and_then(|retry_info| retry_info.delay())
+            }),
+            false => None,
+        };
+        let delay = match requested {
+            Some(requested) if requested > self.max_delay => return None,
+            Some(requested) => requested,
+            None => self.backoff(attempt),
+        };
+        if let Some(hook) = &self.on_retry {
+            hook(&RetryAttempt {
+                attempt,
+                delay,
+                error,
+            });
+        }
+        Some(delay)
+    }
+
+    fn is_retryable(&self, error: &PalmError, status: Option<StatusCode>) -> bool {
+        match (error, status) {
+            (_, Some(status)) => self.retryable_statuses.contains(&status.as_u16()),
+            (PalmError::Transport(err), None) => {
+                (self.retry_on_timeout && err.is_timeout())
+                    || (self.retry_on_connect && err.is_connect())
+            }
+            _ => false,
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = (attempt - 1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        // Any value in [0.5, 1) works, so the randomly seeded std hasher is enough.
+        let random = RandomState::new().build_hasher().finish();
+        delay.mul_f64(0.5 + (random >> 11) as f64 / (1u64 << 54) as f64)
+    }
+}
+
+/// A failed attempt, with what is needed to decide whether to retry it.
+pub(crate) struct Failure {
+    pub(crate) error: PalmError,
+    pub(crate) status: Option<StatusCode>,
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl From<PalmError> for Failure {
+    fn from(error: PalmError) -> Failure {
+        Failure {
+            error,
+            status: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(err: reqwest::Error) -> Failure {
+        Failure::from(PalmError::from(err))
+    }
+}
+
+/// Reads the `Retry-After` header, when it holds a number of seconds.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use crate::error::PalmError;
+    use reqwest::StatusCode;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn server_error(status: StatusCode, body: &str) -> PalmError {
+        PalmError::from_response(status, body)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = RetryPolicy::new()
+            .max_attempts(10)
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(350))
+            .jitter(false);
+        let err = server_error(StatusCode::SERVICE_UNAVAILABLE, "");
+        let status = Some(StatusCode::SERVICE_UNAVAILABLE);
+        let delays: Vec<_> = (1..5)
+            .map(|attempt| policy.next_delay(attempt, &err, status, None).unwrap())
+            .collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(policy.next_delay(10, &err, status, None), None);
+    }
+
+    #[test]
+    fn honors_server_delay() {
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&observed);
+        let policy = RetryPolicy::new()
+            .max_delay(Duration::from_secs(10))
+            .on_retry(move |attempt| recorder.lock().unwrap().push(attempt.delay));
+        let body = r#"{"error": {"code": 429, "message": "quota", "status": "RESOURCE_EXHAUSTED",
+            "details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "2s"}]}}"#;
+        let err = server_error(StatusCode::TOO_MANY_REQUESTS, body);
+        let status = Some(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            policy.next_delay(1, &err, status, None),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.next_delay(1, &err, status, Some(Duration::from_secs(3))),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            policy.next_delay(1, &err, status, Some(Duration::from_secs(60))),
+            None
+        );
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![Duration::from_secs(2), Duration::from_secs(3)]
+        );
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let policy = RetryPolicy::new();
+        let err = server_error(StatusCode::BAD_REQUEST, "");
+        assert_eq!(
+            policy.next_delay(1, &err, Some(StatusCode::BAD_REQUEST), None),
+            None
+        );
+        let err = PalmError::InvalidArgument("temperature".to_string());
+        assert_eq!(policy.next_delay(1, &err, None, None), None);
+    }
+}
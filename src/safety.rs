@@ -0,0 +1,353 @@
+//! Safety policies applied to every request of a client, and reports of blocked content.
+
+use crate::palm::{
+    BlockedReason, ContentFilter, HarmBlockThreshold, HarmCategory, HarmProbability, SafetySetting,
+    TextRes,
+};
+
+const CATEGORIES: [HarmCategory; 6] = [
+    HarmCategory::Derogatory,
+    HarmCategory::Toxicity,
+    HarmCategory::Violence,
+    HarmCategory::Sexual,
+    HarmCategory::Medical,
+    HarmCategory::Dangerous,
+];
+
+/// A threshold per harm category, set on a client with `PalmClientBuilder::safety_policy`.
+///
+/// The policy is merged into every `generate_text()` request: settings of the request take
+/// precedence, and the policy fills in the categories the request leaves unset. `chat()`
+/// requests are left alone, as `generateMessage` takes no safety settings.
+///
+/// # Example
+/// ```
+/// use palm_api::palm::{HarmBlockThreshold, HarmCategory};
+/// use palm_api::safety::SafetyPolicy;
+///
+/// let policy = SafetyPolicy::strict().threshold(HarmCategory::Medical, HarmBlockThreshold::BlockOnlyHigh);
+/// let client = palm_api::palm::PalmClient::builder()
+///     .api_key(API_KEY)
+///     .safety_policy(policy)
+///     .build()
+///     .expect("err");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetyPolicy {
+    settings: Vec<SafetySetting>,
+}
+
+impl Default for SafetyPolicy {
+    /// Returns the thresholds the API applies when a request sets none: derogatory and toxic
+    /// content is blocked from a low probability, other categories from a medium probability.
+    fn default() -> SafetyPolicy {
+        SafetyPolicy::new()
+            .threshold(
+                HarmCategory::Derogatory,
+                HarmBlockThreshold::BlockLowAndAbove,
+            )
+            .threshold(HarmCategory::Toxicity, HarmBlockThreshold::BlockLowAndAbove)
+            .threshold(
+                HarmCategory::Violence,
+                HarmBlockThreshold::BlockMediumAndAbove,
+            )
+            .threshold(
+                HarmCategory::Sexual,
+                HarmBlockThreshold::BlockMediumAndAbove,
+            )
+            .threshold(
+                HarmCategory::Medical,
+                HarmBlockThreshold::BlockMediumAndAbove,
+            )
+            .threshold(
+                HarmCategory::Dangerous,
+                HarmBlockThreshold::BlockMediumAndAbove,
+            )
+    }
+}
+
+impl SafetyPolicy {
+    /// Creates a policy without any threshold.
+    pub fn new() -> SafetyPolicy {
+        SafetyPolicy {
+            settings: Vec::new(),
+        }
+    }
+
+    /// Creates a policy blocking every category from a low probability.
+    pub fn strict() -> SafetyPolicy {
+        SafetyPolicy::all(HarmBlockThreshold::BlockLowAndAbove)
+    }
+
+    /// Creates a policy blocking every category only at a high probability.
+    pub fn permissive() -> SafetyPolicy {
+        SafetyPolicy::all(HarmBlockThreshold::BlockOnlyHigh)
+    }
+
+    fn all(threshold: HarmBlockThreshold) -> SafetyPolicy {
+        CATEGORIES
+            .iter()
+            .fold(SafetyPolicy::new(), |policy, &category| {
+                policy.threshold(category, threshold)
+            })
+    }
+
+    /// Sets the threshold of `category`, replacing the previous one.
+    pub fn threshold(
+        mut self,
+        category: HarmCategory,
+        threshold: HarmBlockThreshold,
+    ) -> SafetyPolicy {
+        match self
+            .settings
+            .iter_mut()
+            .find(|setting| setting.category == category)
+        {
+            Some(setting) => setting.threshold = threshold,
+            None => self.settings.push(SafetySetting {
+                category,
+                threshold,
+            }),
+        }
+        self
+    }
+
+    /// Returns the threshold of `category`, if set.
+    pub fn threshold_for(&self, category: HarmCategory) -> Option<HarmBlockThreshold> {
+        self.settings
+            .iter()
+            .find(|setting| setting.category == category)
+            .map(|setting| setting.threshold)
+    }
+
+    /// Returns the settings of the policy.
+    pub fn settings(&self) -> &[SafetySetting] {
+        &self.settings
+    }
+
+    /// Appends the settings of the categories missing from `settings`.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub(crate) fn merge_into(&self, settings: &mut Vec<SafetySetting>) {
+        for setting in &self.settings {
+            if !settings.iter().any(|set| set.category == setting.category) {
+                settings.push(setting.clone());
+            }
+        }
+    }
+}
+
+impl HarmBlockThreshold {
+    /// Returns whether content rated `probability` passes this threshold.
+    pub fn allows(self, probability: HarmProbability) -> bool {
+        match self {
+            HarmBlockThreshold::BlockLowAndAbove => probability < HarmProbability::Low,
+            HarmBlockThreshold::BlockMediumAndAbove => probability < HarmProbability::Medium,
+            HarmBlockThreshold::BlockOnlyHigh => probability < HarmProbability::High,
+            HarmBlockThreshold::BlockNone => true,
+            HarmBlockThreshold::Unspecified | HarmBlockThreshold::Unknown => false,
+        }
+    }
+
+    /// Returns the strictest threshold that allows content rated `probability`.
+    pub fn strictest_allowing(probability: HarmProbability) -> HarmBlockThreshold {
+        [
+            HarmBlockThreshold::BlockLowAndAbove,
+            HarmBlockThreshold::BlockMediumAndAbove,
+            HarmBlockThreshold::BlockOnlyHigh,
+        ]
+        .into_iter()
+        .find(|threshold| threshold.allows(probability))
+        .unwrap_or(HarmBlockThreshold::BlockNone)
+    }
+}
+
+/// The smallest change to the threshold of a category that would have let the content through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdChange {
+    /// The category of the blocking setting.
+    pub category: HarmCategory,
+    /// The threshold used by the request.
+    pub current: HarmBlockThreshold,
+    /// The highest probability rated in this category.
+    pub probability: HarmProbability,
+    /// The strictest threshold that allows content rated `probability`.
+    pub required: HarmBlockThreshold,
+}
+
+/// What was blocked in a `TextRes`, returned by `TextRes::safety_report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetyReport {
+    /// The number of candidates returned by the model.
+    pub returned_candidates: usize,
+    /// The filters that blocked a candidate or the prompt because of the safety settings.
+    pub blocked: Vec<ContentFilter>,
+    /// The threshold changes implied by the safety feedback, at most one per category.
+    pub threshold_changes: Vec<ThresholdChange>,
+}
+
+impl SafetyReport {
+    /// Returns whether no candidate was returned because of the safety settings.
+    pub fn is_blocked(&self) -> bool {
+        self.returned_candidates == 0 && !self.blocked.is_empty()
+    }
+}
+
+impl TextRes {
+    /// Reports the content blocked by the safety settings, and how the settings would have to
+    /// change to let it through.
+    ///
+    /// # Example
+    /// ```
+    /// let report = text_res.safety_report();
+    /// for change in report.threshold_changes {
+    ///     println!("{:?}: {:?} -> {:?}", change.category, change.current, change.required);
+    /// }
+    /// ```
+    pub fn safety_report(&self) -> SafetyReport {
+        let blocked = self
+            .filters
+            .iter()
+            .flatten()
+            .filter(|filter| filter.reason == BlockedReason::Safety)
+            .cloned()
+            .collect();
+        let mut threshold_changes: Vec<ThresholdChange> = Vec::new();
+        for feedback in self.safety_feedback.iter().flatten() {
+            let (category, probability) = (feedback.rating.category, feedback.rating.probability);
+            if feedback.setting.threshold.allows(probability) {
+                continue;
+            }
+            match threshold_changes
+                .iter_mut()
+                .find(|change| change.category == category)
+            {
+                Some(change) if change.probability >= probability => {}
+                Some(change) => {
+                    change.probability = probability;
+                    change.required = HarmBlockThreshold::strictest_allowing(probability);
+                }
+                None => threshold_changes.push(ThresholdChange {
+                    category,
+                    current: feedback.setting.threshold,
+                    probability,
+                    required: HarmBlockThreshold::strictest_allowing(probability),
+                }),
+            }
+        }
+        SafetyReport {
+            returned_candidates: self.candidates.as_ref().map_or(0, Vec::len),
+            blocked,
+            threshold_changes,
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "blocking", feature = "async")))]
+mod tests {
+    use super::SafetyPolicy;
+    use crate::palm::{HarmBlockThreshold, HarmCategory, HarmProbability, TextRes};
+
+    #[test]
+    fn request_settings_override_policy() {
+        let policy = SafetyPolicy::strict();
+        assert_eq!(policy.settings().len(), 6);
+        let mut text_body = crate::palm::new_text_body();
+        text_body.append_safety_setting(HarmCategory::Medical, HarmBlockThreshold::BlockNone);
+        let mut settings = text_body.safety_settings().to_vec();
+        policy.merge_into(&mut settings);
+        assert_eq!(settings.len(), 6);
+        assert_eq!(settings[0].category, HarmCategory::Medical);
+        assert_eq!(settings[0].threshold, HarmBlockThreshold::BlockNone);
+        assert_eq!(
+            SafetyPolicy::default().threshold_for(HarmCategory::Violence),
+            Some(HarmBlockThreshold::BlockMediumAndAbove)
+        );
+    }
+
+    #[test]
+    fn reports_threshold_changes() {
+        let text_res: TextRes = serde_json::from_str(
+            r#"{"filters": [{"reason": "SAFETY"}],
+                "safetyFeedback": [
+                    {"rating": {"category": "HARM_CATEGORY_VIOLENCE", "probability": "LOW"},
+                     "setting": {"category": "HARM_CATEGORY_VIOLENCE", "threshold": "BLOCK_LOW_AND_ABOVE"}},
+                    {"rating": {"category": "HARM_CATEGORY_VIOLENCE", "probability": "MEDIUM"},
+                     "setting": {"category": "HARM_CATEGORY_VIOLENCE", "threshold": "BLOCK_LOW_AND_ABOVE"}},
+                    {"rating": {"category": "HARM_CATEGORY_TOXICITY", "probability": "NEGLIGIBLE"},
+                     "setting": {"category": "HARM_CATEGORY_TOXICITY", "threshold": "BLOCK_LOW_AND_ABOVE"}}
+                ]}"#,
+        )
+        .expect("err");
+        let report = text_res.safety_report();
+        assert!(report.is_blocked());
+        assert_eq!(report.threshold_changes.len(), 1);
+        let change = &report.threshold_changes[0];
+        assert_eq!(change.category, HarmCategory::Violence);
+        assert_eq!(change.current, HarmBlockThreshold::BlockLowAndAbove);
+        assert_eq!(change.probability, HarmProbability::Medium);
+        assert_eq!(change.required, HarmBlockThreshold::BlockOnlyHigh);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn client_policy_is_merged_into_requests() {
+        use crate::palm::{PalmClient, TextRequest};
+        use crate::test_server::{Reply, TestServer};
+
+        let server = TestServer::start(vec![Reply::json(200, r#"{"candidates": []}"#)]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .safety_policy(
+                SafetyPolicy::new()
+                    .threshold(HarmCategory::Toxicity, HarmBlockThreshold::BlockLowAndAbove),
+            )
+            .build()
+            .expect("err");
+        let text_request = TextRequest::builder()
+            .prompt("The opposite of hot is")
+            .safety_setting(HarmCategory::Violence, HarmBlockThreshold::BlockNone)
+            .build()
+            .expect("err");
+        client
+            .generate_text("text-bison-001".to_string(), text_request)
+            .expect("err");
+
+        let body: serde_json::Value =
+            serde_json::from_str(&server.requests()[0].body).expect("err");
+        assert_eq!(
+            body["safetySettings"],
+            serde_json::json!([
+                {"category": "HARM_CATEGORY_VIOLENCE", "threshold": "BLOCK_NONE"},
+                {"category": "HARM_CATEGORY_TOXICITY", "threshold": "BLOCK_LOW_AND_ABOVE"}
+            ])
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn client_policy_is_not_sent_with_chat() {
+        use crate::palm::{ChatRequest, PalmClient};
+        use crate::test_server::{Reply, TestServer};
+
+        let server = TestServer::start(vec![Reply::json(
+            200,
+            r#"{"candidates": [], "messages": []}"#,
+        )]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .safety_policy(SafetyPolicy::strict())
+            .build()
+            .expect("err");
+        let chat_request = ChatRequest::builder()
+            .message("Hello")
+            .build()
+            .expect("err");
+        client
+            .chat("chat-bison-001".to_string(), chat_request)
+            .expect("err");
+
+        let body: serde_json::Value =
+            serde_json::from_str(&server.requests()[0].body).expect("err");
+        assert!(body.get("safetySettings").is_none());
+    }
+}
@@ -0,0 +1,649 @@
+//! Conversations that keep their own history, and can be saved and restored as JSON.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "async")]
+use crate::palm::AsyncPalmClient;
+#[cfg(feature = "blocking")]
+use crate::palm::PalmClient;
+use crate::palm::{new_text_body, ChatRequest, ChatRes, Message, PalmError, TextBody, TextRes};
+
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the following conversation in a few sentences, \
+    keeping every fact needed to continue it.";
+const SUMMARY_MAX_OUTPUT_TOKENS: u32 = 256;
+
+/// How a `ChatSession` keeps its prompt within the `input_token_limit` of its model.
+///
+/// The prompt is measured with `count_chat_tokens()` before each message is sent, unless it is
+/// short enough to fit for sure. The context and examples are always kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum TruncationStrategy {
+    /// Sends the whole history; long conversations eventually fail with `PalmError::BadRequest`.
+    #[default]
+    None,
+    /// Drops the oldest exchanges of the history until the prompt fits.
+    DropOldestTurns,
+    /// Replaces the oldest exchanges with a summary written by a second model call, added to
+    /// the context. Falls back to dropping the oldest exchanges if the prompt still does not fit.
+    Summarize {
+        /// The text model writing the summary, e.g. `text-bison-001`.
+        model: String,
+        /// The number of recent exchanges that are never summarized.
+        keep_turns: usize,
+    },
+}
+
+/// The serializable part of a `ChatSession`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SessionState {
+    model: String,
+    author: String,
+    request: ChatRequest,
+    // The length of the history before each turn.
+    turns: Vec<usize>,
+    #[serde(default)]
+    truncation: TruncationStrategy,
+    // The context set by the caller, to which the summary is appended.
+    #[serde(default)]
+    context: Option<String>,
+    #[serde(default)]
+    summary: Option<String>,
+}
+
+// What to do next to fit the prompt within the token limit.
+enum Step {
+    Fits,
+    Truncated,
+    Summarize {
+        model: String,
+        text_body: TextBody,
+        messages: usize,
+    },
+}
+
+/// A conversation with a chat model, owning its model, context, examples, settings and history.
+///
+/// Works with `PalmClient`, and with `AsyncPalmClient` when the `async` feature is enabled.
+///
+/// # Example
+/// ```
+/// use palm_api::palm::ChatRequest;
+/// use palm_api::session::ChatSession;
+///
+/// let request = ChatRequest::builder().context("Reply in english").build().expect("err");
+/// let mut session = ChatSession::new(client, "chat-bison-001", request);
+/// let answer = session.send("Hello.").expect("err");
+/// println!("{}", answer.content);
+/// std::fs::write("session.json", session.to_json().expect("err")).expect("err");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChatSession<C> {
+    client: C,
+    state: SessionState,
+}
+
+impl<C> ChatSession<C> {
+    /// Creates a session with `model`.
+    /// `request` holds the context, examples and settings, and the history to start from.
+    pub fn new(client: C, model: impl Into<String>, request: ChatRequest) -> ChatSession<C> {
+        ChatSession {
+            client,
+            state: SessionState {
+                model: model.into(),
+                author: String::new(),
+                context: request.context().map(String::from),
+                request,
+                turns: Vec::new(),
+                truncation: TruncationStrategy::None,
+                summary: None,
+            },
+        }
+    }
+
+    /// Sends the messages of `send()` with `author`, e.g. `"user"`.
+    /// By default messages have no author, and the model tells participants apart by turn.
+    pub fn with_author(mut self, author: impl Into<String>) -> ChatSession<C> {
+        self.state.author = author.into();
+        self
+    }
+
+    /// Sets how the history is truncated when the prompt exceeds the input token limit.
+    /// Defaults to `TruncationStrategy::None`.
+    pub fn with_truncation(mut self, truncation: TruncationStrategy) -> ChatSession<C> {
+        self.state.truncation = truncation;
+        self
+    }
+
+    /// Restores a session saved with `to_json()`.
+    /// Fails with `PalmError::InvalidArgument` if its turns do not match its history.
+    pub fn from_json(client: C, json: &str) -> Result<ChatSession<C>, PalmError> {
+        let state: SessionState = serde_json::from_str(json)?;
+        let len = state.request.messages().len();
+        let increasing = state.turns.windows(2).all(|pair| pair[0] < pair[1]);
+        if !increasing || state.turns.last().is_some_and(|&start| start >= len) {
+            return Err(PalmError::InvalidArgument(format!(
+                "the turns of the session {:?} do not match its history of {} messages",
+                state.turns, len
+            )));
+        }
+        Ok(ChatSession { client, state })
+    }
+
+    /// Saves the session, without its client, as JSON.
+    pub fn to_json(&self) -> Result<String, PalmError> {
+        Ok(serde_json::to_string(&self.state)?)
+    }
+
+    /// Returns the model of the session.
+    pub fn model(&self) -> &str {
+        &self.state.model
+    }
+
+    /// Returns the request sent with every message, whose messages are the history.
+    pub fn request(&self) -> &ChatRequest {
+        &self.state.request
+    }
+
+    /// Returns the messages of the conversation, in chronological order.
+    pub fn history(&self) -> &[Message] {
+        self.state.request.messages()
+    }
+
+    /// Returns the summary of the exchanges removed by `TruncationStrategy::Summarize`.
+    pub fn summary(&self) -> Option<&str> {
+        self.state.summary.as_deref()
+    }
+
+    /// Returns the number of turns sent with `send()`.
+    pub fn turns(&self) -> usize {
+        self.state.turns.len()
+    }
+
+    /// Removes the last message sent with `send()` and the answer of the model.
+    /// Returns `false` if there is no turn to undo.
+    pub fn undo_last_turn(&mut self) -> bool {
+        let turns = self.state.turns.len();
+        turns > 0 && self.rewind_to(turns - 1)
+    }
+
+    /// Keeps the first `turns` turns of the conversation, removing the later ones.
+    /// Returns `false` if the conversation has no more than `turns` turns.
+    pub fn rewind_to(&mut self, turns: usize) -> bool {
+        if turns >= self.state.turns.len() {
+            return false;
+        }
+        let mut messages = self.history().to_vec();
+        messages.truncate(self.state.turns[turns]);
+        self.state.request.set_messages(messages);
+        self.state.turns.truncate(turns);
+        true
+    }
+
+    // Appends `text` to the history.
+    fn begin_turn(&mut self, text: String) {
+        self.state.turns.push(self.history().len());
+        let message = Message::with_author(self.state.author.clone(), text);
+        self.state.request.push_message(message);
+    }
+
+    // Whether the prompt fits in `limit` tokens without counting them: no tokenizer emits
+    // more tokens than bytes, and half of the limit leaves room for the prompt structure.
+    fn fits_for_sure(&self, limit: u32) -> bool {
+        let bytes: usize = self
+            .state
+            .request
+            .prompt_texts()
+            .iter()
+            .map(|text| text.len())
+            .sum();
+        bytes < limit as usize / 2
+    }
+
+    fn truncation_step(&mut self, tokens: u32, limit: u32) -> Result<Step, PalmError> {
+        if tokens <= limit {
+            return Ok(Step::Fits);
+        }
+        let len = self.history().len();
+        if let TruncationStrategy::Summarize { model, keep_turns } = &self.state.truncation {
+            // Exchanges are removed whole, and the last message is the one being sent.
+            let messages = len.saturating_sub(keep_turns * 2 + 1) / 2 * 2;
+            if messages > 0 {
+                return Ok(Step::Summarize {
+                    model: model.clone(),
+                    text_body: self.summary_request(messages),
+                    messages,
+                });
+            }
+        }
+        if len <= 1 {
+            return Err(PalmError::InvalidArgument(format!(
+                "the prompt exceeds the input token limit of {} ({} tokens) without any history",
+                self.state.model, limit
+            )));
+        }
+        self.drop_oldest(self.oldest_exchange());
+        Ok(Step::Truncated)
+    }
+
+    fn summary_request(&self, messages: usize) -> TextBody {
+        let mut prompt = format!("{}\n\n", SUMMARY_INSTRUCTIONS);
+        if let Some(summary) = &self.state.summary {
+            prompt.push_str(&format!("Earlier in the conversation: {}\n", summary));
+        }
+        for (index, message) in self.history()[..messages].iter().enumerate() {
+            let author = match message.author.is_empty() {
+                true => (index % 2).to_string(),
+                false => message.author.clone(),
+            };
+            prompt.push_str(&format!("{}: {}\n", author, message.content));
+        }
+        let mut text_body = new_text_body();
+        text_body.set_text_prompt(prompt);
+        text_body.set_max_output_tokens(SUMMARY_MAX_OUTPUT_TOKENS);
+        text_body
+    }
+
+    fn apply_summary(&mut self, text_res: TextRes, messages: usize) -> Result<(), PalmError> {
+        let candidates = text_res.candidates.unwrap_or_default();
+        let available = candidates.len();
+        let summary = candidates
+            .into_iter()
+            .next()
+            .ok_or(PalmError::MissingCandidate {
+                index: 0,
+                available,
+            })?
+            .output;
+        let context = match &self.state.context {
+            Some(context) => format!(
+                "{}\n\nSummary of the conversation so far: {}",
+                context, summary
+            ),
+            None => format!("Summary of the conversation so far: {}", summary),
+        };
+        self.state.request.set_context(context);
+        self.state.summary = Some(summary);
+        self.drop_oldest(messages);
+        Ok(())
+    }
+
+    // The number of messages of the oldest exchange: the first turn, or the messages the
+    // history started with up to the next one by the same author, never the message being sent.
+    fn oldest_exchange(&self) -> usize {
+        let history = self.history();
+        let last = history.len().saturating_sub(1);
+        let first_turn = self
+            .state
+            .turns
+            .iter()
+            .copied()
+            .find(|&start| start > 0)
+            .map_or(last, |start| start.min(last));
+        if first_turn == 0 {
+            return 0;
+        }
+        let author = &history[0].author;
+        let next = if author.is_empty() {
+            // Without authors, the model tells participants apart by position.
+            Some(2)
+        } else {
+            history[1..first_turn]
+                .iter()
+                .position(|message| &message.author == author)
+                .map(|index| index + 1)
+        };
+        next.filter(|&next| next < first_turn).unwrap_or(first_turn)
+    }
+
+    // Removes the `count` oldest messages, and the turns that started among them.
+    fn drop_oldest(&mut self, count: usize) {
+        let messages = self.history()[count..].to_vec();
+        self.state.request.set_messages(messages);
+        self.state.turns = self
+            .state
+            .turns
+            .iter()
+            .filter(|&&start| start >= count)
+            .map(|start| start - count)
+            .collect();
+    }
+
+    // Appends the first candidate to the history, or removes the turn if the request failed.
+    fn end_turn(&mut self, result: Result<ChatRes, PalmError>) -> Result<Message, PalmError> {
+        let answer = result.and_then(|chat_res| {
+            let candidates = chat_res.candidates.unwrap_or_default();
+            let available = candidates.len();
+            candidates
+                .into_iter()
+                .next()
+                .ok_or(PalmError::MissingCandidate {
+                    index: 0,
+                    available,
+                })
+        });
+        match answer {
+            Ok(answer) => {
+                let message = Message::with_author(answer.author.clone(), answer.content.clone());
+                self.state.request.push_message(message);
+                Ok(answer)
+            }
+            Err(err) => {
+                self.rewind_to(self.state.turns.len() - 1);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl ChatSession<PalmClient> {
+    /// Sends `text` and returns the answer of the model, adding both to the history.
+    /// `text` is not added if the request fails or every candidate was blocked, though the
+    /// history may have been truncated.
+    pub fn send(&mut self, text: impl Into<String>) -> Result<Message, PalmError> {
+        self.begin_turn(text.into());
+        if let Err(err) = self.fit_to_limit() {
+            return self.end_turn(Err(err));
+        }
+        let result = self
+            .client
+            .chat(self.state.model.clone(), self.state.request.clone());
+        self.end_turn(result)
+    }
+
+    fn fit_to_limit(&mut self) -> Result<(), PalmError> {
+        if self.state.truncation == TruncationStrategy::None {
+            return Ok(());
+        }
+        let model = self.state.model.clone();
+        let limit = self.client.get_model(model.clone())?.input_token_limit;
+        while !self.fits_for_sure(limit) {
+            let tokens = self
+                .client
+                .count_chat_tokens(model.clone(), &self.state.request)?;
+            match self.truncation_step(tokens, limit)? {
+                Step::Fits => break,
+                Step::Truncated => {}
+                Step::Summarize {
+                    model,
+                    text_body,
+                    messages,
+                } => {
+                    let text_res = self.client.generate_text(model, text_body)?;
+                    self.apply_summary(text_res, messages)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl ChatSession<AsyncPalmClient> {
+    /// Sends `text` and returns the answer of the model, adding both to the history.
+    /// `text` is not added if the request fails or every candidate was blocked, though the
+    /// history may have been truncated.
+    pub async fn send(&mut self, text: impl Into<String>) -> Result<Message, PalmError> {
+        self.begin_turn(text.into());
+        if let Err(err) = self.fit_to_limit().await {
+            return self.end_turn(Err(err));
+        }
+        let result = self
+            .client
+            .chat(self.state.model.clone(), self.state.request.clone())
+            .await;
+        self.end_turn(result)
+    }
+
+    async fn fit_to_limit(&mut self) -> Result<(), PalmError> {
+        if self.state.truncation == TruncationStrategy::None {
+            return Ok(());
+        }
+        let model = self.state.model.clone();
+        let limit = self
+            .client
+            .get_model(model.clone())
+            .await?
+            .input_token_limit;
+        while !self.fits_for_sure(limit) {
+            let tokens = self
+                .client
+                .count_chat_tokens(model.clone(), &self.state.request)
+                .await?;
+            match self.truncation_step(tokens, limit)? {
+                Step::Fits => break,
+                Step::Truncated => {}
+                Step::Summarize {
+                    model,
+                    text_body,
+                    messages,
+                } => {
+                    let text_res = self.client.generate_text(model, text_body).await?;
+                    self.apply_summary(text_res, messages)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::{ChatSession, TruncationStrategy};
+    use crate::palm::{new_chat_body, ChatRequest, PalmClient, PalmError};
+    use crate::test_server::{Reply, TestServer};
+
+    fn answer(content: &str) -> Reply {
+        Reply::json(
+            200,
+            &format!(
+                r#"{{"messages": [], "candidates": [{{"author": "1", "content": "{}"}}]}}"#,
+                content
+            ),
+        )
+    }
+
+    #[test]
+    fn send_undo_rewind_and_restore() {
+        let server = TestServer::start(vec![
+            answer("Hi!"),
+            answer("Anything."),
+            Reply::json(
+                200,
+                r#"{"messages": [], "filters": [{"reason": "SAFETY"}]}"#,
+            ),
+            answer("Bye!"),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let request = ChatRequest::builder()
+            .context("Reply in english")
+            .build()
+            .expect("err");
+        let mut session =
+            ChatSession::new(client.clone(), "chat-bison-001", request).with_author("user");
+        assert_eq!(session.send("Hello.").expect("err").content, "Hi!");
+        session.send("What can you do?").expect("err");
+        assert_eq!(session.history().len(), 4);
+        assert!(matches!(
+            session.send("Something unsafe."),
+            Err(PalmError::MissingCandidate { .. })
+        ));
+        assert_eq!(session.turns(), 2);
+
+        assert!(session.undo_last_turn());
+        assert_eq!(session.history().len(), 2);
+        assert_eq!(session.history()[1].author, "1");
+
+        let json = session.to_json().expect("err");
+        let mut restored = ChatSession::from_json(client, &json).expect("err");
+        assert_eq!(restored.history(), session.history());
+        assert_eq!(restored.request().context(), Some("Reply in english"));
+        restored.send("Goodbye.").expect("err");
+        let body: serde_json::Value =
+            serde_json::from_str(&server.requests()[3].body).expect("err");
+        assert_eq!(body["prompt"]["messages"][2]["author"], "user");
+        assert_eq!(body["prompt"]["messages"][2]["content"], "Goodbye.");
+
+        assert!(restored.rewind_to(0));
+        assert!(restored.history().is_empty());
+        assert!(!restored.rewind_to(0));
+    }
+
+    #[test]
+    fn from_json_rejects_turns_outside_history() {
+        let request = ChatRequest::builder()
+            .message("Hello.")
+            .message("Hi!")
+            .build()
+            .expect("err");
+        let json = ChatSession::new((), "chat-bison-001", request)
+            .to_json()
+            .expect("err");
+        let mut state: serde_json::Value = serde_json::from_str(&json).expect("err");
+        for turns in [vec![0, 0], vec![1, 0], vec![2]] {
+            state["turns"] = serde_json::json!(turns);
+            assert!(matches!(
+                ChatSession::from_json((), &state.to_string()),
+                Err(PalmError::InvalidArgument(_))
+            ));
+        }
+        state["turns"] = serde_json::json!([0, 1]);
+        assert!(ChatSession::from_json((), &state.to_string()).is_ok());
+    }
+
+    fn model(input_token_limit: u32) -> Reply {
+        Reply::json(
+            200,
+            &format!(
+                r#"{{"name": "models/chat-bison-001", "version": "001", "displayName": "Chat Bison",
+                    "description": "", "inputTokenLimit": {}, "outputTokenLimit": 1024,
+                    "supportedGenerationMethods": ["generateMessage", "countMessageTokens"]}}"#,
+                input_token_limit
+            ),
+        )
+    }
+
+    fn tokens(count: u32) -> Reply {
+        Reply::json(200, &format!(r#"{{"tokenCount": {}}}"#, count))
+    }
+
+    fn sent_messages(body: &str) -> serde_json::Value {
+        let body: serde_json::Value = serde_json::from_str(body).expect("err");
+        body["prompt"]["messages"].clone()
+    }
+
+    #[test]
+    fn drops_oldest_turns_to_fit() {
+        let server = TestServer::start(vec![
+            model(10),
+            tokens(4),
+            answer("Hi!"),
+            tokens(12),
+            tokens(6),
+            answer("Anything."),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let mut session = ChatSession::new(client, "chat-bison-001", new_chat_body())
+            .with_truncation(TruncationStrategy::DropOldestTurns);
+        session.send("Hello.").expect("err");
+        session.send("What can you do?").expect("err");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(
+            sent_messages(&requests[5].body),
+            serde_json::json!([{"content": "What can you do?"}])
+        );
+        assert_eq!(session.history().len(), 2);
+        assert_eq!(session.turns(), 1);
+    }
+
+    #[test]
+    fn drops_seeded_history_by_author() {
+        let server = TestServer::start(vec![model(10), tokens(12), tokens(6), answer("Hi!")]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let request = ChatRequest::builder()
+            .author_message("user", "Hello.")
+            .author_message("guide", "Welcome.")
+            .author_message("helper", "Hi there.")
+            .author_message("user", "Thanks.")
+            .author_message("guide", "Anything else?")
+            .build()
+            .expect("err");
+        let mut session = ChatSession::new(client, "chat-bison-001", request)
+            .with_author("user")
+            .with_truncation(TruncationStrategy::DropOldestTurns);
+        session.send("Where am I?").expect("err");
+
+        let requests = server.requests();
+        assert_eq!(
+            sent_messages(&requests[3].body),
+            serde_json::json!([
+                {"author": "user", "content": "Thanks."},
+                {"author": "guide", "content": "Anything else?"},
+                {"author": "user", "content": "Where am I?"}
+            ])
+        );
+        assert_eq!(session.turns(), 1);
+        assert!(session.undo_last_turn());
+        assert_eq!(session.history().len(), 2);
+    }
+
+    #[test]
+    fn summarizes_old_turns_into_context() {
+        let server = TestServer::start(vec![
+            model(10),
+            tokens(4),
+            answer("Hi!"),
+            tokens(12),
+            Reply::json(
+                200,
+                r#"{"candidates": [{"output": "They greeted.", "safetyRatings": []}]}"#,
+            ),
+            tokens(8),
+            answer("Sure."),
+        ]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let request = ChatRequest::builder()
+            .context("Be brief.")
+            .build()
+            .expect("err");
+        let truncation = TruncationStrategy::Summarize {
+            model: "text-bison-001".to_string(),
+            keep_turns: 0,
+        };
+        let mut session =
+            ChatSession::new(client, "chat-bison-001", request).with_truncation(truncation);
+        session.send("Hello.").expect("err");
+        session.send("Tell me more.").expect("err");
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[4].path,
+            "/v1beta2/models/text-bison-001:generateText"
+        );
+        assert!(requests[4].body.contains("Hello."));
+        let body: serde_json::Value = serde_json::from_str(&requests[6].body).expect("err");
+        assert_eq!(
+            body["prompt"]["context"],
+            "Be brief.\n\nSummary of the conversation so far: They greeted."
+        );
+        assert_eq!(
+            body["prompt"]["messages"],
+            serde_json::json!([{"content": "Tell me more."}])
+        );
+        assert_eq!(session.summary(), Some("They greeted."));
+    }
+}
@@ -0,0 +1,552 @@
+//! An in-memory index of embeddings for semantic search, enabled with the `vector-store` feature.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::embedding::Embedding;
+#[cfg(feature = "mmap")]
+pub use crate::index_file::MappedVectorStore;
+#[cfg(feature = "blocking")]
+use crate::palm::PalmClient;
+use crate::palm::PalmError;
+
+// Rounds of k-means run by `build_index()`.
+const KMEANS_ITERATIONS: usize = 10;
+
+/// A text to index, with its id and metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Document {
+    /// The id of the document, unique in a store.
+    pub id: String,
+    /// The text of the document.
+    pub text: String,
+    /// Metadata returned with the document by searches.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+impl Document {
+    /// Creates a document without metadata.
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Document {
+        Document {
+            id: id.into(),
+            text: text.into(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    /// Sets the metadata `key` to `value`.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Document {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// How `VectorStore::search` finds the nearest documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// Compares the query with every document.
+    #[default]
+    Exact,
+    /// Clusters the documents into `lists` inverted lists with k-means, and only compares the
+    /// query with the documents of the `probes` lists whose centroids are nearest to it.
+    ///
+    /// Searches are approximate: a document in another list may be missed.
+    Ivf {
+        /// The number of clusters.
+        lists: usize,
+        /// The number of clusters searched per query.
+        probes: usize,
+    },
+}
+
+/// A document found by `VectorStore::search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult<'a> {
+    /// The document.
+    pub document: &'a Document,
+    /// The cosine similarity of the document with the query, between -1 and 1.
+    pub score: f64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) document: Document,
+    pub(crate) embedding: Embedding,
+    pub(crate) list: Option<usize>,
+}
+
+/// Embeddings of documents keyed by document id, searched by cosine similarity.
+///
+/// Every embedding of a store must come from the same model.
+/// Embeddings are normalized when inserted.
+///
+/// # Example
+/// ```
+/// use palm_api::vector_store::{Document, VectorStore};
+///
+/// let mut store = VectorStore::new();
+/// store
+///     .add_texts(&client, "embedding-gecko-001".to_string(), vec![
+///         Document::new("rust", "Rust is a systems programming language.").with_metadata("lang", "en"),
+///         Document::new("python", "Python is a scripting language."),
+///     ])
+///     .expect("err");
+/// let query = client.generate_embeddings("embedding-gecko-001".to_string(), "memory safety".to_string()).expect("err");
+/// for result in store.search(&query, 1).expect("err") {
+///     println!("{} {}", result.document.id, result.score);
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct VectorStore {
+    pub(crate) kind: IndexKind,
+    pub(crate) entries: Vec<Entry>,
+    pub(crate) ids: HashMap<String, usize>,
+    pub(crate) centroids: Vec<Embedding>,
+    pub(crate) lists: Vec<Vec<usize>>,
+}
+
+impl VectorStore {
+    /// Creates an empty store searched exactly.
+    pub fn new() -> VectorStore {
+        VectorStore::default()
+    }
+
+    /// Creates an empty store searched with an index of `kind`.
+    ///
+    /// An `IndexKind::Ivf` index is trained by `build_index()`; until then searches are exact.
+    pub fn with_index(kind: IndexKind) -> VectorStore {
+        VectorStore {
+            kind,
+            ..VectorStore::default()
+        }
+    }
+
+    /// Returns the kind of index used by `search`.
+    pub fn index_kind(&self) -> IndexKind {
+        self.kind
+    }
+
+    /// Returns the number of documents.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the store has no document.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the model of the embeddings, if any document was inserted.
+    pub fn model(&self) -> Option<&str> {
+        self.entries.first().map(|entry| entry.embedding.model())
+    }
+
+    /// Returns the dimension of the embeddings, if any document was inserted.
+    pub fn dimension(&self) -> Option<usize> {
+        self.entries
+            .first()
+            .map(|entry| entry.embedding.dimension())
+    }
+
+    /// Returns the document `id`.
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.ids.get(id).map(|&index| &self.entries[index].document)
+    }
+
+    /// Returns the documents, in no particular order.
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.entries.iter().map(|entry| &entry.document)
+    }
+
+    /// Inserts `document` with its `embedding`, replacing any document with the same id.
+    ///
+    /// Returns `PalmError::InvalidArgument` if the embedding does not come from the model,
+    /// or have the dimension, of the embeddings already in the store.
+    pub fn insert(&mut self, document: Document, embedding: Embedding) -> Result<(), PalmError> {
+        self.check_embedding(&embedding)?;
+        let embedding = embedding.normalize();
+        self.remove(&document.id);
+        let index = self.entries.len();
+        let list = self.nearest_list(&embedding);
+        if let Some(list) = list {
+            self.lists[list].push(index);
+        }
+        self.ids.insert(document.id.clone(), index);
+        self.entries.push(Entry {
+            document,
+            embedding,
+            list,
+        });
+        Ok(())
+    }
+
+    /// Embeds the text of `documents` with `batch_embed_texts()` and inserts them.
+    ///
+    /// Documents whose embedding failed are skipped; the first error is returned once the
+    /// other documents have been inserted.
+    #[cfg(feature = "blocking")]
+    pub fn add_texts(
+        &mut self,
+        client: &PalmClient,
+        model: String,
+        documents: Vec<Document>,
+    ) -> Result<(), PalmError> {
+        let texts = documents
+            .iter()
+            .map(|document| document.text.clone())
+            .collect();
+        let embeddings = client.batch_embed_texts(model, texts);
+        let mut first_error = None;
+        for (document, embedding) in documents.into_iter().zip(embeddings) {
+            if let Err(err) = embedding.and_then(|embedding| self.insert(document, embedding)) {
+                first_error.get_or_insert(err);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Removes the document `id`, returning it if it was in the store.
+    pub fn remove(&mut self, id: &str) -> Option<Document> {
+        let index = self.ids.remove(id)?;
+        let entry = self.entries.swap_remove(index);
+        if let Some(list) = entry.list {
+            self.lists[list].retain(|&member| member != index);
+        }
+        // The last entry moved to `index`.
+        if let Some(moved) = self.entries.get(index) {
+            let previous = self.entries.len();
+            self.ids.insert(moved.document.id.clone(), index);
+            if let Some(list) = moved.list {
+                for member in &mut self.lists[list] {
+                    if *member == previous {
+                        *member = index;
+                    }
+                }
+            }
+        }
+        Some(entry.document)
+    }
+
+    /// Returns the `k` documents most similar to `query`, most similar first.
+    ///
+    /// Returns `PalmError::InvalidArgument` if the query does not come from the model,
+    /// or have the dimension, of the embeddings in the store.
+    pub fn search(&self, query: &Embedding, k: usize) -> Result<Vec<SearchResult<'_>>, PalmError> {
+        let probes = match self.kind {
+            IndexKind::Ivf { probes, .. } if !self.centroids.is_empty() => probes,
+            _ => return self.search_exact(query, k),
+        };
+        self.check_embedding(query)?;
+        let query = query.clone().normalize();
+        let scored = search_lists(&self.centroids, &self.lists, probes, &query, k, |index| {
+            query.dot_unchecked(&self.entries[index].embedding)
+        });
+        Ok(self.results(scored))
+    }
+
+    /// Returns the `k` documents most similar to `query`, comparing it with every document.
+    pub fn search_exact(
+        &self,
+        query: &Embedding,
+        k: usize,
+    ) -> Result<Vec<SearchResult<'_>>, PalmError> {
+        self.check_embedding(query)?;
+        let query = query.clone().normalize();
+        let candidates = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (index, query.dot_unchecked(&entry.embedding)));
+        Ok(self.results(top_k(candidates, k)))
+    }
+
+    /// Trains the `IndexKind::Ivf` index on the documents in the store.
+    ///
+    /// Documents inserted later are added to the list of their nearest centroid; rebuild the
+    /// index once the corpus has changed significantly. Does nothing for `IndexKind::Exact`.
+    pub fn build_index(&mut self) {
+        let IndexKind::Ivf { lists, .. } = self.kind else {
+            return;
+        };
+        let lists = lists.min(self.entries.len());
+        self.centroids.clear();
+        self.lists.clear();
+        if lists == 0 {
+            for entry in &mut self.entries {
+                entry.list = None;
+            }
+            return;
+        }
+        // Starts from documents spread across the store, so that training is deterministic.
+        let step = self.entries.len() / lists;
+        self.centroids = (0..lists)
+            .map(|list| self.entries[list * step].embedding.clone().into_f64())
+            .collect();
+        for _ in 0..KMEANS_ITERATIONS {
+            self.assign_lists();
+            self.update_centroids();
+        }
+        self.assign_lists();
+    }
+
+    fn assign_lists(&mut self) {
+        self.lists = vec![Vec::new(); self.centroids.len()];
+        for index in 0..self.entries.len() {
+            let list = self.nearest_list(&self.entries[index].embedding);
+            if let Some(list) = list {
+                self.lists[list].push(index);
+            }
+            self.entries[index].list = list;
+        }
+    }
+
+    // Moves each centroid to the normalized mean of its list; empty lists keep their centroid.
+    fn update_centroids(&mut self) {
+        let (model, dimension) = match self.entries.first() {
+            Some(entry) => (entry.embedding.model(), entry.embedding.dimension()),
+            None => return,
+        };
+        for (list, members) in self.lists.iter().enumerate() {
+            if members.is_empty() {
+                continue;
+            }
+            let mut sum = vec![0.0; dimension];
+            for &index in members {
+                for (total, value) in sum.iter_mut().zip(self.entries[index].embedding.iter()) {
+                    *total += value;
+                }
+            }
+            self.centroids[list] = Embedding::new(model, sum).normalize();
+        }
+    }
+
+    fn nearest_list(&self, embedding: &Embedding) -> Option<usize> {
+        top_k(
+            self.centroids
+                .iter()
+                .enumerate()
+                .map(|(list, centroid)| (list, embedding.dot_unchecked(centroid))),
+            1,
+        )
+        .first()
+        .map(|&(list, _)| list)
+    }
+
+    fn results(&self, scored: Vec<(usize, f64)>) -> Vec<SearchResult<'_>> {
+        scored
+            .into_iter()
+            .map(|(index, score)| SearchResult {
+                document: &self.entries[index].document,
+                score,
+            })
+            .collect()
+    }
+
+    fn check_embedding(&self, embedding: &Embedding) -> Result<(), PalmError> {
+        match self.entries.first() {
+            Some(entry) => check_embedding(
+                entry.embedding.model(),
+                entry.embedding.dimension(),
+                embedding,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+// Checks that `embedding` can be compared with the embeddings of `model` and `dimension`.
+pub(crate) fn check_embedding(
+    model: &str,
+    dimension: usize,
+    embedding: &Embedding,
+) -> Result<(), PalmError> {
+    if model_name(model) != model_name(embedding.model()) {
+        return Err(PalmError::InvalidArgument(format!(
+            "the store holds embeddings of {}, not {}",
+            model,
+            embedding.model()
+        )));
+    }
+    if dimension != embedding.dimension() {
+        return Err(PalmError::InvalidArgument(format!(
+            "the store holds embeddings of dimension {}, not {}",
+            dimension,
+            embedding.dimension()
+        )));
+    }
+    Ok(())
+}
+
+// `generate_embeddings()` accepts both `embedding-gecko-001` and `models/embedding-gecko-001`.
+fn model_name(model: &str) -> &str {
+    model.strip_prefix("models/").unwrap_or(model)
+}
+
+// Returns the `k` documents scored highest by `score` among the `probes` lists whose centroids
+// are nearest to the normalized `query`.
+pub(crate) fn search_lists(
+    centroids: &[Embedding],
+    lists: &[Vec<usize>],
+    probes: usize,
+    query: &Embedding,
+    k: usize,
+    score: impl Fn(usize) -> f64,
+) -> Vec<(usize, f64)> {
+    let nearest = top_k(
+        centroids
+            .iter()
+            .enumerate()
+            .map(|(list, centroid)| (list, query.dot_unchecked(centroid))),
+        probes.max(1),
+    );
+    let candidates = nearest
+        .into_iter()
+        .flat_map(|(list, _)| lists[list].iter().map(|&index| (index, score(index))));
+    top_k(candidates, k)
+}
+
+// Returns the `k` pairs with the highest score, highest first.
+pub(crate) fn top_k(scored: impl Iterator<Item = (usize, f64)>, k: usize) -> Vec<(usize, f64)> {
+    let mut scored: Vec<(usize, f64)> = scored.collect();
+    let by_score = |a: &(usize, f64), b: &(usize, f64)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+    if k == 0 {
+        return Vec::new();
+    }
+    if k < scored.len() {
+        scored.select_nth_unstable_by(k - 1, by_score);
+        scored.truncate(k);
+    }
+    scored.sort_unstable_by(by_score);
+    scored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Document, IndexKind, VectorStore};
+    use crate::embedding::Embedding;
+    use crate::palm::PalmError;
+
+    const MODEL: &str = "embedding-gecko-001";
+
+    fn embedding(values: &[f64]) -> Embedding {
+        Embedding::new(MODEL, values.to_vec())
+    }
+
+    #[test]
+    fn exact_search_ranks_by_cosine_similarity() {
+        let mut store = VectorStore::new();
+        store
+            .insert(
+                Document::new("east", "East").with_metadata("axis", "x"),
+                embedding(&[1.0, 0.0]),
+            )
+            .expect("err");
+        store
+            .insert(Document::new("north", "North"), embedding(&[0.0, 2.0]))
+            .expect("err");
+        store
+            .insert(
+                Document::new("north-east", "North-east"),
+                embedding(&[1.0, 1.0]),
+            )
+            .expect("err");
+
+        let results = store.search(&embedding(&[1.0, 0.2]), 2).expect("err");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].document.id, "east");
+        assert_eq!(results[0].document.metadata["axis"], "x");
+        assert_eq!(results[1].document.id, "north-east");
+        assert!(results[0].score > results[1].score);
+
+        assert_eq!(
+            store.remove("east").map(|document| document.id),
+            Some("east".to_string())
+        );
+        store
+            .insert(Document::new("north", "Up"), embedding(&[0.0, 1.0]))
+            .expect("err");
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("north").expect("err").text, "Up");
+        let results = store.search(&embedding(&[1.0, 0.2]), 5).expect("err");
+        assert_eq!(results[0].document.id, "north-east");
+
+        assert!(matches!(
+            store.search(&embedding(&[1.0]), 1),
+            Err(PalmError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            store.insert(
+                Document::new("other", "Other"),
+                Embedding::new("other-model", vec![1.0, 0.0])
+            ),
+            Err(PalmError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn ivf_search_probes_nearest_lists() {
+        let mut store = VectorStore::with_index(IndexKind::Ivf {
+            lists: 4,
+            probes: 1,
+        });
+        for index in 0..40 {
+            let angle = index as f64 * std::f64::consts::TAU / 40.0;
+            store
+                .insert(
+                    Document::new(format!("doc-{}", index), ""),
+                    embedding(&[angle.cos(), angle.sin()]),
+                )
+                .expect("err");
+        }
+        store.build_index();
+        store
+            .insert(Document::new("late", ""), embedding(&[-1.0, -0.01]))
+            .expect("err");
+        store.remove("doc-0");
+
+        for query in [[1.0, 0.1], [-1.0, 0.0], [0.2, -1.0]] {
+            let query = embedding(&query);
+            let approximate = store.search(&query, 1).expect("err");
+            let exact = store.search_exact(&query, 1).expect("err");
+            assert_eq!(approximate[0].document.id, exact[0].document.id);
+        }
+        assert_eq!(
+            store.search(&embedding(&[-1.0, 0.0]), 2).expect("err")[0]
+                .document
+                .id,
+            "doc-20"
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn add_texts_embeds_and_indexes_documents() {
+        use crate::palm::PalmClient;
+        use crate::test_server::{Reply, TestServer};
+
+        let server = TestServer::start(vec![Reply::json(
+            200,
+            r#"{"embeddings": [{"value": [1.0, 0.0]}, {"value": [0.0, 1.0]}]}"#,
+        )]);
+        let client = PalmClient::builder()
+            .base_url(&server.url)
+            .build()
+            .expect("err");
+        let mut store = VectorStore::new();
+        store
+            .add_texts(
+                &client,
+                MODEL.to_string(),
+                vec![
+                    Document::new("rust", "Rust"),
+                    Document::new("python", "Python"),
+                ],
+            )
+            .expect("err");
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.dimension(), Some(2));
+        let results = store.search(&embedding(&[0.1, 1.0]), 1).expect("err");
+        assert_eq!(results[0].document.id, "python");
+    }
+}